@@ -0,0 +1,97 @@
+use common::{EncryptedContent, WrappedKey};
+use openssl::encrypt::{Decrypter, Encrypter};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{HasPublic, PKey, PKeyRef, Private, Public};
+use openssl::rsa::Padding;
+use openssl::sign::Verifier;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+const KEY_LEN: usize = 32;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+pub fn fingerprint<T: HasPublic>(key: &PKeyRef<T>) -> String {
+    let der = key
+        .public_key_to_der()
+        .expect("failed to encode public key as DER");
+
+    common::key_fingerprint(&der)
+}
+
+/// Checks a SHA-256 signature the same way the server does
+pub fn verify(key: &PKey<Public>, data: &[u8], signature: &[u8]) -> bool {
+    let Ok(mut verifier) = Verifier::new(MessageDigest::sha256(), key) else {
+        return false;
+    };
+
+    verifier.update(data).is_ok() && verifier.verify(signature).unwrap_or(false)
+}
+
+pub fn encrypt<'a>(
+    plaintext: &[u8],
+    recipients: impl IntoIterator<Item = &'a PKey<Public>>,
+) -> Result<EncryptedContent, openssl::error::ErrorStack> {
+    let mut key = [0; KEY_LEN];
+    let mut iv = [0; IV_LEN];
+    openssl::rand::rand_bytes(&mut key)?;
+    openssl::rand::rand_bytes(&mut iv)?;
+
+    let mut tag = vec![0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&iv),
+        &[],
+        plaintext,
+        &mut tag,
+    )?;
+
+    let mut keys = Vec::new();
+    for recipient in recipients {
+        let mut encrypter = Encrypter::new(recipient)?;
+        encrypter.set_rsa_padding(Padding::PKCS1_OAEP)?;
+
+        let mut wrapped = vec![0; encrypter.encrypt_len(&key)?];
+        let len = encrypter.encrypt(&key, &mut wrapped)?;
+        wrapped.truncate(len);
+
+        keys.push(WrappedKey {
+            recipient: fingerprint(recipient),
+            key: wrapped,
+        });
+    }
+
+    Ok(EncryptedContent {
+        iv: iv.to_vec(),
+        ciphertext,
+        tag,
+        keys,
+    })
+}
+
+/// Returns `None` if the message wasn't encrypted for `pkey` or can't be
+/// decrypted
+pub fn decrypt(content: &EncryptedContent, pkey: &PKey<Private>) -> Option<Vec<u8>> {
+    let own_fingerprint = fingerprint(pkey);
+    let wrapped = content
+        .keys
+        .iter()
+        .find(|k| k.recipient == own_fingerprint)?;
+
+    let mut decrypter = Decrypter::new(pkey).ok()?;
+    decrypter.set_rsa_padding(Padding::PKCS1_OAEP).ok()?;
+
+    let mut key = vec![0; decrypter.decrypt_len(&wrapped.key).ok()?];
+    let len = decrypter.decrypt(&wrapped.key, &mut key).ok()?;
+    key.truncate(len);
+
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&content.iv),
+        &[],
+        &content.ciphertext,
+        &content.tag,
+    )
+    .ok()
+}
@@ -0,0 +1,284 @@
+use openssl::pkey::{PKey, Public};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub enum HistoryEntry {
+    Message(ChatMessage),
+    Notice(String),
+    Warning(String),
+}
+
+pub struct ChatMessage {
+    /// Server-assigned ID. Direct messages don't have one.
+    pub id: Option<String>,
+    pub sender: String,
+    /// Key the message was signed with. Edits and deletions must be signed
+    /// with it too.
+    pub sender_key: Option<PKey<Public>>,
+    pub text: String,
+    /// Whether the signature matched the sender's public key
+    pub verified: bool,
+    /// Whether the sender's key differs from the one pinned for their name
+    pub key_changed: bool,
+    /// Set for direct messages
+    pub recipient: Option<String>,
+    /// When the server received it, in milliseconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    /// When it disappears, in milliseconds since the Unix epoch
+    pub expires_at: Option<i64>,
+    pub edited: bool,
+    pub deleted: bool,
+    /// Set on messages this client sent until the server answers
+    pub correlation_id: Option<String>,
+    pub delivery: Delivery,
+}
+
+pub enum Delivery {
+    /// Sent, but the server hasn't acknowledged it yet
+    Pending,
+    Delivered,
+    /// Rejected by the server, with its reason
+    Failed(String),
+}
+
+impl ChatMessage {
+    /// A message this client just sent, shown until the server answers
+    pub fn outgoing(
+        sender: String,
+        sender_key: PKey<Public>,
+        text: String,
+        correlation_id: String,
+    ) -> ChatMessage {
+        ChatMessage {
+            id: None,
+            sender,
+            sender_key: Some(sender_key),
+            text,
+            verified: true,
+            key_changed: false,
+            recipient: None,
+            timestamp: None,
+            expires_at: None,
+            edited: false,
+            deleted: false,
+            correlation_id: Some(correlation_id),
+            delivery: Delivery::Pending,
+        }
+    }
+}
+
+impl HistoryEntry {
+    pub fn to_line(&self) -> Line<'_> {
+        let warning_style = Style::default().fg(Color::Red);
+        let faint_style = Style::default().fg(Color::DarkGray);
+
+        match self {
+            HistoryEntry::Message(message) => {
+                let mut spans = Vec::new();
+                let time = message
+                    .timestamp
+                    .and_then(chrono::DateTime::from_timestamp_millis);
+                if let Some(time) = time {
+                    let local = time.with_timezone(&chrono::Local);
+                    spans.push(Span::styled(
+                        local.format("%H:%M ").to_string(),
+                        faint_style,
+                    ));
+                }
+                match &message.delivery {
+                    Delivery::Pending => spans.push(Span::styled("[sending] ", faint_style)),
+                    Delivery::Delivered => {}
+                    Delivery::Failed(reason) => spans.push(Span::styled(
+                        format!("[FAILED: {}] ", reason),
+                        warning_style,
+                    )),
+                }
+                if !message.verified {
+                    spans.push(Span::styled("[UNVERIFIED] ", warning_style));
+                }
+                if message.key_changed {
+                    spans.push(Span::styled("[KEY CHANGED] ", warning_style));
+                }
+
+                match &message.recipient {
+                    Some(recipient) => {
+                        spans.push(Span::styled("[DM] ", Style::default().fg(Color::Magenta)));
+                        spans.push(Span::raw(format!("<{} -> {}> ", message.sender, recipient)));
+                    }
+                    None => spans.push(Span::raw(format!("<{}> ", message.sender))),
+                }
+
+                if message.deleted {
+                    spans.push(Span::styled(
+                        "message deleted",
+                        faint_style.add_modifier(Modifier::ITALIC),
+                    ));
+                } else {
+                    spans.push(Span::raw(message.text.as_str()));
+                    if message.edited {
+                        spans.push(Span::styled(" (edited)", faint_style));
+                    }
+                    let expiry = message
+                        .expires_at
+                        .and_then(chrono::DateTime::from_timestamp_millis);
+                    if let Some(expiry) = expiry {
+                        let local = expiry.with_timezone(&chrono::Local);
+                        spans.push(Span::styled(
+                            local.format(" (disappears %H:%M)").to_string(),
+                            faint_style,
+                        ));
+                    }
+                }
+                Line::from(spans)
+            }
+            HistoryEntry::Notice(text) => Line::styled(format!("* {}", text), faint_style),
+            HistoryEntry::Warning(text) => Line::styled(format!("! {}", text), warning_style),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Room {
+    pub history: Vec<HistoryEntry>,
+    /// IDs of the messages in `history`, so one delivered both live and in a
+    /// history page only shows once
+    pub ids: HashSet<String>,
+    /// Cursor for the next older page of history
+    pub cursor: Option<String>,
+    /// Whether there's history older than what's loaded
+    pub has_more: bool,
+    /// A history request is waiting for its response
+    pub loading: bool,
+    /// How many lines the view is scrolled up from the newest one
+    pub scroll: usize,
+    /// Seconds messages sent to this room are kept for, or `None` to keep
+    /// them forever
+    pub ttl: Option<u32>,
+    /// Name of everyone typing here and when they last said so, by
+    /// fingerprint
+    pub typing: HashMap<String, (String, Instant)>,
+    /// ID of the newest message you've read here, from any of your devices
+    pub read_up_to: Option<String>,
+    /// Name of everyone else who's read this room and the newest message they
+    /// read, by fingerprint
+    pub seen_by: HashMap<String, (String, String)>,
+}
+
+impl Room {
+    /// Drops messages whose expiry has passed. Returns whether any were.
+    pub fn remove_expired(&mut self, now: i64) -> bool {
+        let before = self.history.len();
+        self.history.retain(|entry| match entry {
+            HistoryEntry::Message(message) => message.expires_at.is_none_or(|t| t > now),
+            _ => true,
+        });
+
+        self.history.len() != before
+    }
+
+    /// Forgets typists who haven't refreshed their indicator in `timeout`.
+    /// Returns whether any were.
+    pub fn remove_stale_typing(&mut self, timeout: Duration) -> bool {
+        let before = self.typing.len();
+        self.typing
+            .retain(|_, (_, since)| since.elapsed() < timeout);
+
+        self.typing.len() != before
+    }
+
+    /// "alice is typing…" line for the room, if anyone is
+    pub fn typing_notice(&self) -> Option<String> {
+        let mut names: Vec<&str> = self.typing.values().map(|(n, _)| n.as_str()).collect();
+        names.sort();
+
+        match names.as_slice() {
+            [] => None,
+            [name] => Some(format!("{} is typing…", name)),
+            [first, second] => Some(format!("{} and {} are typing…", first, second)),
+            _ => Some("several people are typing…".to_owned()),
+        }
+    }
+
+    /// ID of the newest message with one. Message IDs are UUIDv7s, so they
+    /// compare in time order.
+    pub fn newest_id(&self) -> Option<&str> {
+        self.history.iter().rev().find_map(|entry| match entry {
+            HistoryEntry::Message(message) => message.id.as_deref(),
+            _ => None,
+        })
+    }
+
+    /// How many loaded messages from others are newer than `read_up_to`
+    pub fn unread(&self, own_key: &PKey<Public>) -> usize {
+        let read_up_to = self.read_up_to.as_deref().unwrap_or_default();
+
+        self.history
+            .iter()
+            .filter(|entry| match entry {
+                HistoryEntry::Message(message) => {
+                    !message.deleted
+                        && message.id.as_deref().is_some_and(|id| id > read_up_to)
+                        && !message
+                            .sender_key
+                            .as_ref()
+                            .is_some_and(|k| k.public_eq(own_key))
+                }
+                _ => false,
+            })
+            .count()
+    }
+
+    /// "seen by alice, bob" line for the newest message, if anyone else has
+    /// read it
+    pub fn seen_by_notice(&self) -> Option<String> {
+        let newest = self.newest_id()?;
+        let mut names: Vec<&str> = self
+            .seen_by
+            .values()
+            .filter(|(_, id)| id.as_str() >= newest)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort();
+
+        (!names.is_empty()).then(|| format!("seen by {}", names.join(", ")))
+    }
+
+    pub fn message_mut(&mut self, id: &str) -> Option<&mut ChatMessage> {
+        self.history.iter_mut().find_map(|entry| match entry {
+            HistoryEntry::Message(message) if message.id.as_deref() == Some(id) => Some(message),
+            _ => None,
+        })
+    }
+
+    /// A message this client sent that's waiting for an answer to
+    /// `correlation_id`
+    pub fn pending_mut(&mut self, correlation_id: &str) -> Option<&mut ChatMessage> {
+        self.history.iter_mut().find_map(|entry| match entry {
+            HistoryEntry::Message(message)
+                if message.correlation_id.as_deref() == Some(correlation_id) =>
+            {
+                Some(message)
+            }
+            _ => None,
+        })
+    }
+
+    /// The newest message signed by `key` that can still be edited or deleted
+    pub fn last_message_by(&self, key: &PKey<Public>) -> Option<&ChatMessage> {
+        self.history.iter().rev().find_map(|entry| match entry {
+            HistoryEntry::Message(message)
+                if message.id.is_some()
+                    && !message.deleted
+                    && message
+                        .sender_key
+                        .as_ref()
+                        .is_some_and(|k| k.public_eq(key)) =>
+            {
+                Some(message)
+            }
+            _ => None,
+        })
+    }
+}
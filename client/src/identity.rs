@@ -0,0 +1,73 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::symm::Cipher;
+
+/// If set, identity keys are stored encrypted with this passphrase
+const PASSPHRASE_VAR: &str = "ETEEDIR_PASSPHRASE";
+
+/// `$XDG_CONFIG_HOME/eteedir`, falling back to `~/.config/eteedir`
+pub fn config_dir() -> PathBuf {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("eteedir"),
+        _ => {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            PathBuf::from(home).join(".config").join("eteedir")
+        }
+    }
+}
+
+pub fn default_identity_path() -> PathBuf {
+    config_dir().join("identity.pem")
+}
+
+/// Loads the private key at `path`, generating and saving a new one if the
+/// file doesn't exist yet
+pub fn load_or_generate(path: &Path) -> Result<PKey<Private>, Box<dyn Error>> {
+    let passphrase = std::env::var(PASSPHRASE_VAR).ok();
+
+    match std::fs::read(path) {
+        Ok(pem) => {
+            let pkey = match &passphrase {
+                Some(p) => PKey::private_key_from_pem_passphrase(&pem, p.as_bytes())?,
+                None => PKey::private_key_from_pem(&pem)?,
+            };
+            Ok(pkey)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let rsa = Rsa::generate(2048)?;
+            let pkey = PKey::from_rsa(rsa)?;
+
+            let pem = match &passphrase {
+                Some(p) => {
+                    pkey.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), p.as_bytes())?
+                }
+                None => pkey.private_key_to_pem_pkcs8()?,
+            };
+            save(path, &pem)?;
+
+            Ok(pkey)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(path: &Path, pem: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(pem)
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Trust-on-first-use pins from display names to key fingerprints, stored as
+/// one `name fingerprint` pair per line
+pub struct KnownKeys {
+    path: PathBuf,
+    keys: HashMap<String, String>,
+}
+
+pub enum KeyStatus {
+    /// First time seeing this name, the key has now been pinned
+    New,
+    Known,
+    /// The name was previously seen with a different key
+    Changed {
+        pinned: String,
+    },
+}
+
+impl KnownKeys {
+    pub fn load(path: &Path) -> std::io::Result<KnownKeys> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let keys = contents
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, fingerprint)| (name.to_owned(), fingerprint.trim().to_owned()))
+            .collect();
+
+        Ok(KnownKeys {
+            path: path.to_owned(),
+            keys,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.keys.get(name).map(String::as_str)
+    }
+
+    /// Compares `fingerprint` against the pinned key for `name`, pinning it if
+    /// the name hasn't been seen before
+    pub fn check(&mut self, name: &str, fingerprint: &str) -> KeyStatus {
+        match self.keys.get(name) {
+            Some(pinned) if pinned == fingerprint => KeyStatus::Known,
+            Some(pinned) => KeyStatus::Changed {
+                pinned: pinned.clone(),
+            },
+            None => {
+                self.keys.insert(name.to_owned(), fingerprint.to_owned());
+                if let Err(e) = self.append(name, fingerprint) {
+                    eprintln!("failed to save known key for {}: {}", name, e);
+                }
+                KeyStatus::New
+            }
+        }
+    }
+
+    fn append(&self, name: &str, fingerprint: &str) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{} {}", name, fingerprint)
+    }
+}
+
+/// Splits a fingerprint into space-separated groups of four characters so two
+/// people can read it out to each other
+pub fn display_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .as_bytes()
+        .chunks(4)
+        .map(|c| String::from_utf8_lossy(c))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -1,38 +1,110 @@
-use common::{MessagePacket, Packet, ServerboundHandshake};
+mod crypto;
+mod history;
+mod identity;
+mod known_keys;
+
+use common::{
+    feature, Ack, ClientboundChallenge, DecodeError, DeleteMessage, DirectMessagePacket,
+    EditMessage, Encoding, EncryptedContent, ErrorPacket, Frame, HistoryPage, HistoryRequest,
+    JoinRoom, LeaveRoom, ListMembers, LookupName, Member, MemberList, MessagePacket, NameInfo,
+    Packet, PeerKeyPacket, Presence, QueueAck, QueueBatchEnd, ReadPositions, ReadUpTo,
+    RegisterName, ServerboundChallengeResponse, ServerboundHandshake, TypingStarted, TypingStopped,
+};
 use crossterm::event::{EventStream, KeyCode};
 use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::{FutureExt, SinkExt, StreamExt};
+use history::{ChatMessage, Delivery, HistoryEntry, Room};
+use known_keys::{KeyStatus, KnownKeys};
 use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
-use openssl::rsa::Rsa;
+use openssl::pkey::{PKey, Public};
 use openssl::sign::Signer;
 use ratatui::backend::CrosstermBackend;
 use ratatui::layout::Rect;
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
 use ratatui::widgets::{Block, Borders, Paragraph};
+use std::collections::{HashMap, HashSet};
 use std::io::Error;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tui_textarea::TextArea;
 
+const DEFAULT_ROOM: &str = "general";
+const HISTORY_PAGE_SIZE: u32 = 50;
+/// Width of the online member panel, including its border
+const MEMBER_PANEL_WIDTH: u16 = 24;
+
 struct App<'a> {
     terminal: ratatui::Terminal<CrosstermBackend<std::io::Stdout>>,
-    inbound_message_send: mpsc::Sender<String>,
-    inbound_message_recv: mpsc::Receiver<String>,
-    outbound_message_send: mpsc::Sender<String>,
+    inbound_message_send: mpsc::Sender<Frame>,
+    inbound_message_recv: mpsc::Receiver<Frame>,
+    outbound_message_send: mpsc::Sender<Frame>,
     should_exit: bool,
     input: TextArea<'a>,
-    history: Vec<String>,
+    /// Every joined room
+    rooms: HashMap<String, Room>,
+    /// Rooms left this session. Messages still on their way from them are
+    /// dropped instead of rejoining the room.
+    left_rooms: HashSet<String>,
+    /// Room messages are sent to and whose history is shown
+    room: String,
+    /// Height of the history pane when it was last drawn
+    history_height: usize,
 
     pkey: PKey<openssl::pkey::Private>,
+    name: String,
+    /// Public keys of everyone messages are encrypted for, by fingerprint.
+    /// Includes subscribers of joined rooms that are offline.
+    peers: HashMap<String, PKey<Public>>,
+    /// Fingerprint each display name was last seen with this session
+    names: HashMap<String, String>,
+    known_keys: KnownKeys,
+    /// Last correlation ID given to a packet
+    last_correlation_id: u64,
+    /// Version the server chose in the handshake
+    protocol_version: u32,
+    /// Features both sides support, from the handshake
+    features: Vec<String>,
+    /// How packets to the server are encoded
+    encoding: Encoding,
+    /// Correlation ID of a /register waiting for an answer, and the name it
+    /// asked for
+    pending_name: Option<(String, String)>,
+    /// Room the last [`TypingStarted`] went to and when, while the input box
+    /// has text
+    typing_in: Option<(String, Instant)>,
+    /// Whether the current room got messages since its read position was last
+    /// sent. They're marked read on the next tick rather than one by one.
+    unread: bool,
+    /// Everyone online, by fingerprint
+    online: HashMap<String, Member>,
+    /// Whether the online member panel is shown
+    show_members: bool,
 }
 
 impl<'a> App<'a> {
-    pub fn new(outbound_message_send: mpsc::Sender<String>) -> App<'a> {
+    pub fn new(
+        outbound_message_send: mpsc::Sender<Frame>,
+        pkey: PKey<openssl::pkey::Private>,
+        name: String,
+        known_keys: KnownKeys,
+    ) -> App<'a> {
         let (message_send, message_recv) = tokio::sync::mpsc::channel(16);
 
-        let rsa = Rsa::generate(2048).expect("failed to generate RSA key");
+        let own_public_key = PKey::public_key_from_der(
+            &pkey
+                .public_key_to_der()
+                .expect("failed to encode public key as DER"),
+        )
+        .expect("failed to decode own public key");
+
+        let own_fingerprint = crypto::fingerprint(&own_public_key);
+        let names = HashMap::from([(name.clone(), own_fingerprint.clone())]);
+        let peers = HashMap::from([(own_fingerprint, own_public_key)]);
 
         App {
             terminal: ratatui::init(),
@@ -41,9 +113,25 @@ impl<'a> App<'a> {
             outbound_message_send,
             should_exit: false,
             input: Self::create_input_textarea(),
-            history: Vec::new(),
+            rooms: HashMap::from([(DEFAULT_ROOM.to_owned(), Room::default())]),
+            left_rooms: HashSet::new(),
+            room: DEFAULT_ROOM.to_owned(),
+            history_height: 0,
 
-            pkey: PKey::from_rsa(rsa).expect("failed to convert RSA to PKey"),
+            pkey,
+            name,
+            peers,
+            names,
+            known_keys,
+            last_correlation_id: 0,
+            protocol_version: common::MIN_PROTOCOL_VERSION,
+            features: Vec::new(),
+            encoding: Encoding::Json,
+            pending_name: None,
+            typing_in: None,
+            unread: false,
+            online: HashMap::new(),
+            show_members: false,
         }
     }
 
@@ -58,43 +146,990 @@ impl<'a> App<'a> {
                 KeyCode::Enter => {
                     let msg = self.input.lines()[0].clone();
                     self.input = Self::create_input_textarea();
-                    self.send_message(msg);
+
+                    match msg.strip_prefix('/') {
+                        Some(command) => self.run_command(command),
+                        None => self.send_message(msg),
+                    }
                 }
 
+                KeyCode::F(2) => self.show_members = !self.show_members,
+
+                KeyCode::PageUp => self.scroll_history(self.history_height / 2, true),
+                KeyCode::PageDown => self.scroll_history(self.history_height / 2, false),
+
                 _ => {
                     let input_event: tui_textarea::Input = event.into();
                     self.input.input(input_event);
                 }
             }
+
+            self.update_typing();
         }
 
         self.draw();
     }
 
-    pub fn packet_received(&mut self, raw_data: String) {
-        let (id, json_data) = common::network_decode(&raw_data).unwrap();
+    pub fn packet_received(&mut self, frame: Frame) {
+        let (id, body) = match frame.split() {
+            Ok(parts) => parts,
+            Err(e) => {
+                self.push_history(HistoryEntry::Warning(format!(
+                    "bad packet from server: {}",
+                    e
+                )));
+                return;
+            }
+        };
 
         macro_rules! parse_packets {
             ($($packet_type:ident => $func:ident),* $(,)?) => {
-                match id {
+                let result = match id {
                 $(
-                    $packet_type::ID => self.$func(serde_json::from_str(json_data).unwrap()),
+                    $packet_type::ID => $packet_type::decode(body).map(|p| self.$func(p)),
                 )*
-                    other => eprintln!("unexpected packet ID {}", other),
+                    other => Err(DecodeError::UnknownPacket(other.to_owned())),
+                };
+
+                if let Err(e) = result {
+                    self.push_history(HistoryEntry::Warning(format!("bad packet from server: {}", e)));
+                    self.draw();
                 }
             }
         }
 
         parse_packets!(
             MessagePacket => handle_message,
+            DirectMessagePacket => handle_direct_message,
+            PeerKeyPacket => handle_peer_key,
+            ClientboundChallenge => handle_challenge,
+            HistoryPage => handle_history_page,
+            EditMessage => handle_edit_message,
+            DeleteMessage => handle_delete_message,
+            Ack => handle_ack,
+            ErrorPacket => handle_error,
+            NameInfo => handle_name_info,
+            Presence => handle_presence,
+            MemberList => handle_member_list,
+            TypingStarted => handle_typing_started,
+            TypingStopped => handle_typing_stopped,
+            ReadUpTo => handle_read_up_to,
+            ReadPositions => handle_read_positions,
+            QueueBatchEnd => handle_queue_batch_end,
         );
     }
 
+    fn handle_ack(&mut self, ack: Ack) {
+        if let Some(name) = self.take_pending_name(&ack.correlation_id) {
+            let own_fingerprint = crypto::fingerprint(&self.pkey);
+            self.check_known_key(&name, &own_fingerprint);
+            self.push_history(HistoryEntry::Notice(format!("registered name {}", name)));
+            self.name = name;
+            self.draw();
+            return;
+        }
+
+        for room in self.rooms.values_mut() {
+            let Some(message) = room.pending_mut(&ack.correlation_id) else {
+                continue;
+            };
+
+            message.correlation_id = None;
+            message.delivery = Delivery::Delivered;
+            message.id = ack.id.clone();
+            room.ids.extend(ack.id);
+            break;
+        }
+        self.draw();
+    }
+
+    fn handle_error(&mut self, error: ErrorPacket) {
+        let pending_name = error
+            .correlation_id
+            .as_deref()
+            .and_then(|correlation_id| self.take_pending_name(correlation_id));
+        if let Some(name) = pending_name {
+            self.push_history(HistoryEntry::Warning(format!(
+                "couldn't register {}: {}",
+                name, error.message
+            )));
+            self.draw();
+            return;
+        }
+
+        let pending = error.correlation_id.as_deref().and_then(|correlation_id| {
+            self.rooms
+                .values_mut()
+                .find_map(|room| room.pending_mut(correlation_id))
+        });
+
+        match pending {
+            Some(message) => {
+                message.correlation_id = None;
+                message.delivery = Delivery::Failed(error.message);
+            }
+            None => self.push_history(HistoryEntry::Warning(format!(
+                "server error: {}",
+                error.message
+            ))),
+        }
+        self.draw();
+    }
+
+    /// The name a /register asked for, if `correlation_id` answers it
+    fn take_pending_name(&mut self, correlation_id: &str) -> Option<String> {
+        match self.pending_name.take() {
+            Some((id, name)) if id == correlation_id => Some(name),
+            other => {
+                self.pending_name = other;
+                None
+            }
+        }
+    }
+
+    fn handle_name_info(&mut self, info: NameInfo) {
+        let key = info
+            .public_key
+            .and_then(|pem| PKey::public_key_from_pem(pem.as_bytes()).ok());
+
+        let entry = match key {
+            Some(key) => {
+                let fingerprint = crypto::fingerprint(&key);
+                match self.known_keys.get(&info.name) {
+                    Some(pinned) if pinned != fingerprint => HistoryEntry::Warning(format!(
+                        "{} is registered to {} but pinned to {}",
+                        info.name,
+                        known_keys::display_fingerprint(&fingerprint),
+                        known_keys::display_fingerprint(pinned)
+                    )),
+                    _ => HistoryEntry::Notice(format!(
+                        "{} is registered to {}",
+                        info.name,
+                        known_keys::display_fingerprint(&fingerprint)
+                    )),
+                }
+            }
+            None => HistoryEntry::Notice(format!("{} isn't registered", info.name)),
+        };
+        self.push_history(entry);
+        self.draw();
+    }
+
     fn handle_message(&mut self, message: MessagePacket) {
-        self.history.push(message.content);
+        if !self.rooms.contains_key(&message.room) {
+            if self.left_rooms.contains(&message.room) {
+                return;
+            }
+
+            // Queued while we were offline for a room the server still has us
+            // in, but this session hasn't joined yet
+            self.rooms.insert(message.room.clone(), Room::default());
+            self.queue_packet(JoinRoom {
+                room: message.room.clone(),
+            });
+            self.request_history(&message.room);
+        }
+        let room = &self.rooms[&message.room];
+
+        if let Some(id) = &message.id {
+            if room.ids.contains(id) {
+                // Our own message coming back after its Ack, with the fields
+                // only the server knows
+                let room = self.rooms.get_mut(&message.room).unwrap();
+                if let Some(existing) = room.message_mut(id) {
+                    existing.timestamp = message.timestamp;
+                    existing.expires_at = message.expires_at;
+                }
+                self.draw();
+                return;
+            }
+        }
+
+        let room_name = message.room.clone();
+        let id = message.id.clone();
+        let entry = self.room_message_entry(message);
+
+        let room = self.rooms.get_mut(&room_name).unwrap();
+        room.ids.extend(id);
+        room.history.push(entry);
+        if room.scroll > 0 {
+            room.scroll += 1;
+        }
+        if room_name == self.room {
+            self.unread = true;
+        }
+        self.draw();
+    }
+
+    fn handle_history_page(&mut self, page: HistoryPage) {
+        let Some(room) = self.rooms.get(&page.room) else {
+            return;
+        };
+
+        let messages: Vec<MessagePacket> = page
+            .messages
+            .into_iter()
+            .filter(|message| !message.id.as_ref().is_some_and(|id| room.ids.contains(id)))
+            .collect();
+
+        let mut ids = Vec::new();
+        let mut entries = Vec::new();
+        for message in messages {
+            ids.extend(message.id.clone());
+            entries.push(self.room_message_entry(message));
+        }
+
+        let room = self.rooms.get_mut(&page.room).unwrap();
+        room.ids.extend(ids);
+        room.history.splice(0..0, entries);
+        room.has_more = page.cursor.is_some();
+        room.cursor = page.cursor;
+        room.loading = false;
+        if page.room == self.room {
+            self.unread = true;
+        }
+        self.draw();
+    }
+
+    fn handle_edit_message(&mut self, edit: EditMessage) {
+        let text = self.decrypt_text(&edit.content);
+        let Some(room) = self.rooms.get_mut(&edit.room) else {
+            return;
+        };
+
+        let Some(message) = room.message_mut(&edit.id) else {
+            return;
+        };
+
+        let signed_data = EditMessage::signed_data(&edit.room, &edit.id, &edit.content);
+        let signed_by_author = message
+            .sender_key
+            .as_ref()
+            .is_some_and(|key| crypto::verify(key, &signed_data, &edit.signature));
+
+        if signed_by_author {
+            message.text = text;
+            message.edited = true;
+        } else {
+            let warning = format!(
+                "ignored an edit of {}'s message that they didn't sign",
+                message.sender
+            );
+            room.history.push(HistoryEntry::Warning(warning));
+        }
         self.draw();
     }
 
+    fn handle_delete_message(&mut self, delete: DeleteMessage) {
+        let Some(room) = self.rooms.get_mut(&delete.room) else {
+            return;
+        };
+
+        let Some(message) = room.message_mut(&delete.id) else {
+            return;
+        };
+
+        let signed_data = DeleteMessage::signed_data(&delete.room, &delete.id);
+        let signed_by_author = message
+            .sender_key
+            .as_ref()
+            .is_some_and(|key| crypto::verify(key, &signed_data, &delete.signature));
+
+        if signed_by_author {
+            message.text.clear();
+            message.deleted = true;
+        } else {
+            let warning = format!(
+                "ignored a deletion of {}'s message that they didn't sign",
+                message.sender
+            );
+            room.history.push(HistoryEntry::Warning(warning));
+        }
+        self.draw();
+    }
+
+    fn handle_direct_message(&mut self, message: DirectMessagePacket) {
+        let mut entry = self.chat_message(
+            &message.content,
+            message.content.as_bytes(),
+            &message.signature,
+            message.sender,
+            message.sender_name,
+        );
+        entry.recipient = Some(self.display_name(&message.recipient));
+
+        self.push_history(HistoryEntry::Message(entry));
+        self.draw();
+    }
+
+    fn room_message_entry(&mut self, message: MessagePacket) -> HistoryEntry {
+        // An edit replaces the signature along with the content
+        let signed_data = match (&message.id, message.edited_at) {
+            (Some(id), Some(_)) => EditMessage::signed_data(&message.room, id, &message.content),
+            _ => message.content.clone().into_bytes(),
+        };
+
+        let mut entry = self.chat_message(
+            &message.content,
+            &signed_data,
+            &message.signature,
+            message.sender,
+            message.sender_name,
+        );
+        entry.id = message.id;
+        entry.timestamp = message.timestamp;
+        entry.expires_at = message.expires_at;
+        entry.edited = message.edited_at.is_some();
+
+        HistoryEntry::Message(entry)
+    }
+
+    /// Decrypts a received message and checks `signature` against
+    /// `signed_data`
+    fn chat_message(
+        &mut self,
+        content: &str,
+        signed_data: &[u8],
+        signature: &[u8],
+        sender: Option<String>,
+        sender_name: Option<String>,
+    ) -> ChatMessage {
+        let sender_key = sender.and_then(|pem| PKey::public_key_from_pem(pem.as_bytes()).ok());
+
+        let verified = sender_key
+            .as_ref()
+            .is_some_and(|key| crypto::verify(key, signed_data, signature));
+
+        let (sender, key_changed) = match (&sender_key, sender_name) {
+            (Some(key), Some(name)) => {
+                let key_changed = !self.check_known_key(&name, &crypto::fingerprint(key));
+                (name, key_changed)
+            }
+            (Some(key), None) => (crypto::fingerprint(key)[..8].to_owned(), false),
+            (None, _) => ("unknown".to_owned(), false),
+        };
+
+        ChatMessage {
+            id: None,
+            sender,
+            sender_key,
+            text: self.decrypt_text(content),
+            verified,
+            key_changed,
+            recipient: None,
+            timestamp: None,
+            expires_at: None,
+            edited: false,
+            deleted: false,
+            correlation_id: None,
+            delivery: Delivery::Delivered,
+        }
+    }
+
+    fn decrypt_text(&self, content: &str) -> String {
+        serde_json::from_str::<EncryptedContent>(content)
+            .ok()
+            .and_then(|content| crypto::decrypt(&content, &self.pkey))
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .unwrap_or_else(|| "[message not encrypted for you]".to_owned())
+    }
+
+    /// Name last seen with a fingerprint, or a short form of the fingerprint
+    fn display_name(&self, fingerprint: &str) -> String {
+        self.names
+            .iter()
+            .find(|(_, f)| *f == fingerprint)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| fingerprint.chars().take(8).collect())
+    }
+
+    fn handle_challenge(&mut self, challenge: ClientboundChallenge) {
+        if common::negotiate_version(challenge.protocol_version) != Some(challenge.protocol_version)
+        {
+            self.push_history(HistoryEntry::Warning(format!(
+                "server chose protocol version {}, which this client doesn't speak",
+                challenge.protocol_version
+            )));
+            self.draw();
+            return;
+        }
+        self.protocol_version = challenge.protocol_version;
+        self.features = challenge.features;
+        if self.features.iter().any(|f| f == feature::CBOR) {
+            self.encoding = Encoding::Cbor;
+        }
+
+        let signature = self.sign(challenge.nonce.as_bytes());
+        self.queue_packet(ServerboundChallengeResponse { signature });
+
+        let rooms: Vec<String> = self.rooms.keys().cloned().collect();
+        for room in rooms {
+            self.queue_packet(JoinRoom { room: room.clone() });
+            self.request_history(&room);
+        }
+
+        if common::supports(self.protocol_version, &self.features, feature::PRESENCE) {
+            self.queue_packet(ListMembers {});
+        }
+    }
+
+    fn handle_typing_started(&mut self, packet: TypingStarted) {
+        let (Some(fingerprint), Some(name)) = (packet.fingerprint, packet.sender_name) else {
+            return;
+        };
+        // Other devices using this key don't count
+        if fingerprint == crypto::fingerprint(&self.pkey) {
+            return;
+        }
+
+        let Some(room) = self.rooms.get_mut(&packet.room) else {
+            return;
+        };
+        let was_typing = room
+            .typing
+            .insert(fingerprint, (name, Instant::now()))
+            .is_some();
+
+        if !was_typing {
+            self.draw();
+        }
+    }
+
+    fn handle_typing_stopped(&mut self, packet: TypingStopped) {
+        let Some(fingerprint) = packet.fingerprint else {
+            return;
+        };
+        let Some(room) = self.rooms.get_mut(&packet.room) else {
+            return;
+        };
+
+        if room.typing.remove(&fingerprint).is_some() {
+            self.draw();
+        }
+    }
+
+    fn handle_read_up_to(&mut self, packet: ReadUpTo) {
+        self.apply_read_position(packet);
+        self.draw();
+    }
+
+    fn handle_read_positions(&mut self, packet: ReadPositions) {
+        for position in packet.positions {
+            self.apply_read_position(position);
+        }
+        self.draw();
+    }
+
+    /// Moves your or someone else's read position in a room forward
+    fn apply_read_position(&mut self, position: ReadUpTo) {
+        let (Some(fingerprint), Some(name)) = (position.fingerprint, position.sender_name) else {
+            return;
+        };
+        let own_fingerprint = crypto::fingerprint(&self.pkey);
+        let Some(room) = self.rooms.get_mut(&position.room) else {
+            return;
+        };
+
+        if fingerprint == own_fingerprint {
+            if room.read_up_to.as_ref() < Some(&position.message_id) {
+                room.read_up_to = Some(position.message_id);
+            }
+            return;
+        }
+
+        match room.seen_by.get_mut(&fingerprint) {
+            Some((_, id)) if *id >= position.message_id => {}
+            _ => {
+                room.seen_by
+                    .insert(fingerprint, (name, position.message_id));
+            }
+        }
+    }
+
+    /// Marks the current room read if messages arrived in it since the last
+    /// tick
+    pub fn mark_read_if_unread(&mut self) {
+        if std::mem::take(&mut self.unread) {
+            self.mark_read();
+        }
+    }
+
+    /// Tells the server you've read the current room up to its newest message
+    fn mark_read(&mut self) {
+        if !common::supports(self.protocol_version, &self.features, feature::RECEIPTS) {
+            return;
+        }
+
+        let room = self.rooms.get_mut(&self.room).unwrap();
+        let Some(newest) = room.newest_id() else {
+            return;
+        };
+        if room.read_up_to.as_deref() >= Some(newest) {
+            return;
+        }
+
+        let message_id = newest.to_owned();
+        room.read_up_to = Some(message_id.clone());
+        self.queue_packet(ReadUpTo {
+            room: self.room.clone(),
+            message_id,
+            fingerprint: None,
+            sender_name: None,
+        });
+    }
+
+    // Packets are handled in order, so everything in the batch is already
+    // shown
+    fn handle_queue_batch_end(&mut self, end: QueueBatchEnd) {
+        self.queue_packet(QueueAck { up_to: end.up_to });
+    }
+
+    fn handle_member_list(&mut self, list: MemberList) {
+        self.online = list
+            .members
+            .into_iter()
+            .filter_map(|member| {
+                let key = PKey::public_key_from_pem(member.public_key.as_bytes()).ok()?;
+                Some((crypto::fingerprint(&key), member))
+            })
+            .collect();
+        self.draw();
+    }
+
+    fn handle_presence(&mut self, presence: Presence) {
+        let Ok(key) = PKey::public_key_from_pem(presence.public_key.as_bytes()) else {
+            return;
+        };
+        let fingerprint = crypto::fingerprint(&key);
+
+        if presence.devices == 0 {
+            if self.online.remove(&fingerprint).is_some() {
+                self.push_history(HistoryEntry::Notice(format!(
+                    "{} went offline",
+                    presence.name
+                )));
+            }
+        } else {
+            let member = Member {
+                public_key: presence.public_key,
+                name: presence.name.clone(),
+                devices: presence.devices,
+            };
+            if self.online.insert(fingerprint, member).is_none() {
+                self.push_history(HistoryEntry::Notice(format!(
+                    "{} came online",
+                    presence.name
+                )));
+            }
+        }
+        self.draw();
+    }
+
+    /// Asks for the page of history before what's already loaded
+    fn request_history(&mut self, room_name: &str) {
+        let Some(room) = self.rooms.get_mut(room_name) else {
+            return;
+        };
+
+        if room.loading {
+            return;
+        }
+        room.loading = true;
+
+        let request = HistoryRequest {
+            room: room_name.to_owned(),
+            cursor: room.cursor.clone(),
+            limit: HISTORY_PAGE_SIZE,
+        };
+        self.queue_packet(request);
+    }
+
+    fn scroll_history(&mut self, lines: usize, up: bool) {
+        let height = self.history_height;
+        let Some(room) = self.rooms.get_mut(&self.room) else {
+            return;
+        };
+
+        let max_scroll = room.history.len().saturating_sub(height);
+        room.scroll = if up {
+            (room.scroll + lines).min(max_scroll)
+        } else {
+            room.scroll.saturating_sub(lines)
+        };
+
+        if up && room.scroll == max_scroll && room.has_more {
+            let name = self.room.clone();
+            self.request_history(&name);
+        }
+    }
+
+    fn handle_peer_key(&mut self, packet: PeerKeyPacket) {
+        match PKey::public_key_from_pem(packet.public_key.as_bytes()) {
+            Ok(key) => {
+                let fingerprint = crypto::fingerprint(&key);
+                self.check_known_key(&packet.name, &fingerprint);
+                if let Some(member) = self.online.get_mut(&fingerprint) {
+                    member.name = packet.name;
+                }
+                self.peers.insert(fingerprint, key);
+            }
+            Err(e) => eprintln!("invalid peer key: {}", e),
+        }
+        self.draw();
+    }
+
+    /// Pins `fingerprint` for `name` on first use. Returns false if the name is
+    /// pinned to a different key, warning the first time the key is seen.
+    fn check_known_key(&mut self, name: &str, fingerprint: &str) -> bool {
+        let previous = self.names.insert(name.to_owned(), fingerprint.to_owned());
+        let first_sighting = previous.as_deref() != Some(fingerprint);
+
+        match self.known_keys.check(name, fingerprint) {
+            KeyStatus::Known => true,
+            KeyStatus::New => {
+                self.push_history(HistoryEntry::Notice(format!(
+                    "pinned key {} for {}",
+                    known_keys::display_fingerprint(fingerprint),
+                    name
+                )));
+                true
+            }
+            KeyStatus::Changed { pinned } => {
+                if first_sighting {
+                    self.push_history(HistoryEntry::Warning(format!(
+                        "WARNING: {} is using a different key than before! Pinned {}, got {}. \
+                         Check it with /verify {}",
+                        name,
+                        known_keys::display_fingerprint(&pinned),
+                        known_keys::display_fingerprint(fingerprint),
+                        name
+                    )));
+                }
+                false
+            }
+        }
+    }
+
+    fn run_command(&mut self, command: &str) {
+        let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+
+        match name {
+            "verify" => self.verify_command(args.trim()),
+            "join" => self.join_command(args.trim()),
+            "leave" => self.leave_command(),
+            "msg" => self.msg_command(args),
+            "edit" => self.edit_command(args),
+            "delete" => self.delete_command(),
+            "ttl" => self.ttl_command(args.trim()),
+            "register" => self.register_command(args.trim()),
+            "whois" => self.whois_command(args.trim()),
+            other => {
+                self.push_history(HistoryEntry::Warning(format!("unknown command /{}", other)))
+            }
+        }
+    }
+
+    fn join_command(&mut self, room: &str) {
+        if room.is_empty() {
+            self.push_history(HistoryEntry::Warning("usage: /join <room>".to_owned()));
+            return;
+        }
+
+        self.left_rooms.remove(room);
+        if !self.rooms.contains_key(room) {
+            self.rooms.insert(room.to_owned(), Room::default());
+            self.queue_packet(JoinRoom {
+                room: room.to_owned(),
+            });
+            self.request_history(room);
+        }
+
+        self.room = room.to_owned();
+        self.mark_read();
+    }
+
+    fn leave_command(&mut self) {
+        let Some(next_room) = self.rooms.keys().find(|r| **r != self.room).cloned() else {
+            self.push_history(HistoryEntry::Warning(
+                "can't leave the only room you're in".to_owned(),
+            ));
+            return;
+        };
+
+        let room = std::mem::replace(&mut self.room, next_room);
+        self.rooms.remove(&room);
+        self.left_rooms.insert(room.clone());
+        self.queue_packet(LeaveRoom { room });
+        self.mark_read();
+    }
+
+    fn msg_command(&mut self, args: &str) {
+        let Some((name, text)) = args.trim_start().split_once(' ') else {
+            self.push_history(HistoryEntry::Warning(
+                "usage: /msg <name> <text>".to_owned(),
+            ));
+            return;
+        };
+
+        let fingerprint = self
+            .names
+            .get(name)
+            .map(String::as_str)
+            .or_else(|| self.known_keys.get(name));
+
+        let Some(recipient_key) = fingerprint.and_then(|f| self.peers.get(f)) else {
+            self.push_history(HistoryEntry::Warning(format!(
+                "no public key known for {}, they need to be seen online first",
+                name
+            )));
+            return;
+        };
+
+        let recipients = [recipient_key, self.own_public_key()];
+        let encrypted =
+            crypto::encrypt(text.as_bytes(), recipients).expect("failed to encrypt message");
+        let content = serde_json::to_string(&encrypted).expect("failed to encode message");
+        let signature = self.sign(content.as_bytes());
+        let recipient = crypto::fingerprint(recipient_key);
+
+        let correlation_id = self.next_correlation_id();
+        let mut entry = ChatMessage::outgoing(
+            self.name.clone(),
+            self.own_public_key().clone(),
+            text.to_owned(),
+            correlation_id.clone(),
+        );
+        entry.recipient = Some(name.to_owned());
+        self.push_history(HistoryEntry::Message(entry));
+
+        self.queue_packet(DirectMessagePacket {
+            recipient,
+            content,
+            signature,
+            sender: None,
+            sender_name: None,
+            correlation_id: Some(correlation_id),
+        });
+    }
+
+    /// Replaces the text of your last message in the current room
+    fn edit_command(&mut self, text: &str) {
+        if !self.require_feature(feature::EDIT, "editing") {
+            return;
+        }
+
+        if text.is_empty() {
+            self.push_history(HistoryEntry::Warning("usage: /edit <text>".to_owned()));
+            return;
+        }
+
+        let Some(id) = self.own_last_message_id() else {
+            self.push_history(HistoryEntry::Warning("nothing to edit".to_owned()));
+            return;
+        };
+
+        let content = self.encrypt_for_peers(text);
+        let signature = self.sign(&EditMessage::signed_data(&self.room, &id, &content));
+        let correlation_id = Some(self.next_correlation_id());
+        self.queue_packet(EditMessage {
+            room: self.room.clone(),
+            id,
+            content,
+            signature,
+            correlation_id,
+        });
+    }
+
+    /// Deletes your last message in the current room
+    fn delete_command(&mut self) {
+        if !self.require_feature(feature::EDIT, "deleting") {
+            return;
+        }
+
+        let Some(id) = self.own_last_message_id() else {
+            self.push_history(HistoryEntry::Warning("nothing to delete".to_owned()));
+            return;
+        };
+
+        let signature = self.sign(&DeleteMessage::signed_data(&self.room, &id));
+        let correlation_id = Some(self.next_correlation_id());
+        self.queue_packet(DeleteMessage {
+            room: self.room.clone(),
+            id,
+            signature,
+            correlation_id,
+        });
+    }
+
+    /// Sets how long messages you send to the current room are kept
+    fn ttl_command(&mut self, arg: &str) {
+        if !self.require_feature(feature::DISAPPEARING, "disappearing messages") {
+            return;
+        }
+
+        let ttl = match arg {
+            "off" => None,
+            seconds => match seconds.parse::<u32>() {
+                Ok(seconds) if seconds > 0 => Some(seconds),
+                _ => {
+                    self.push_history(HistoryEntry::Warning(
+                        "usage: /ttl <seconds|off>".to_owned(),
+                    ));
+                    return;
+                }
+            },
+        };
+
+        let room = self.rooms.get_mut(&self.room).unwrap();
+        room.ttl = ttl;
+
+        let notice = match ttl {
+            Some(seconds) => format!("messages you send here disappear after {}s", seconds),
+            None => "messages you send here are kept".to_owned(),
+        };
+        self.push_history(HistoryEntry::Notice(notice));
+    }
+
+    /// Registers a name to your key so nobody else can use it
+    fn register_command(&mut self, name: &str) {
+        if !self.require_feature(feature::NAMES, "registering names") {
+            return;
+        }
+
+        if name.is_empty() {
+            self.push_history(HistoryEntry::Warning("usage: /register <name>".to_owned()));
+            return;
+        }
+
+        let correlation_id = self.next_correlation_id();
+        self.pending_name = Some((correlation_id.clone(), name.to_owned()));
+        self.queue_packet(RegisterName {
+            name: name.to_owned(),
+            correlation_id: Some(correlation_id),
+        });
+    }
+
+    /// Asks the server which key a name is registered to
+    fn whois_command(&mut self, name: &str) {
+        if !self.require_feature(feature::NAMES, "looking up names") {
+            return;
+        }
+
+        if name.is_empty() {
+            self.push_history(HistoryEntry::Warning("usage: /whois <name>".to_owned()));
+            return;
+        }
+
+        self.queue_packet(LookupName {
+            name: name.to_owned(),
+        });
+    }
+
+    /// Removes disappearing messages whose time is up
+    pub fn prune_expired(&mut self) {
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let typing_timeout = Duration::from_secs(2 * common::TYPING_REFRESH_SECS);
+
+        let mut changed = false;
+        for room in self.rooms.values_mut() {
+            changed |= room.remove_expired(now);
+            changed |= room.remove_stale_typing(typing_timeout);
+        }
+
+        if changed {
+            self.draw();
+        }
+    }
+
+    /// Tells the current room whether you're typing, repeating it every
+    /// [`common::TYPING_REFRESH_SECS`] while the input box has text
+    pub fn update_typing(&mut self) {
+        if !common::supports(self.protocol_version, &self.features, feature::TYPING) {
+            return;
+        }
+
+        let text = &self.input.lines()[0];
+        let typing = !text.is_empty() && !text.starts_with('/');
+        let refresh = Duration::from_secs(common::TYPING_REFRESH_SECS);
+
+        match self.typing_in.take() {
+            Some((room, sent)) if typing && room == self.room && sent.elapsed() < refresh => {
+                self.typing_in = Some((room, sent));
+                return;
+            }
+            Some((room, _)) if !typing || room != self.room => {
+                self.queue_packet(TypingStopped {
+                    room,
+                    fingerprint: None,
+                });
+            }
+            _ => {}
+        }
+
+        if typing {
+            self.queue_packet(TypingStarted {
+                room: self.room.clone(),
+                fingerprint: None,
+                sender_name: None,
+            });
+            self.typing_in = Some((self.room.clone(), Instant::now()));
+        }
+    }
+
+    /// Whether the server supports `feature`, warning that `what` isn't
+    /// available if not
+    fn require_feature(&mut self, feature: &str, what: &str) -> bool {
+        let supported = common::supports(self.protocol_version, &self.features, feature);
+        if !supported {
+            self.push_history(HistoryEntry::Warning(format!(
+                "the server doesn't support {}",
+                what
+            )));
+        }
+
+        supported
+    }
+
+    fn own_last_message_id(&self) -> Option<String> {
+        self.rooms[&self.room]
+            .last_message_by(self.own_public_key())
+            .and_then(|message| message.id.clone())
+    }
+
+    fn own_public_key(&self) -> &PKey<Public> {
+        &self.peers[&crypto::fingerprint(&self.pkey)]
+    }
+
+    fn next_correlation_id(&mut self) -> String {
+        self.last_correlation_id += 1;
+        self.last_correlation_id.to_string()
+    }
+
+    fn verify_command(&mut self, name: &str) {
+        if name.is_empty() {
+            self.push_history(HistoryEntry::Warning("usage: /verify <name>".to_owned()));
+            return;
+        }
+
+        let current = self.names.get(name).map(String::as_str);
+        let entry = match (self.known_keys.get(name), current) {
+            (Some(pinned), Some(current)) if pinned != current => HistoryEntry::Warning(format!(
+                "{} is pinned to {} but is currently using {}",
+                name,
+                known_keys::display_fingerprint(pinned),
+                known_keys::display_fingerprint(current)
+            )),
+            (Some(fingerprint), _) | (None, Some(fingerprint)) => HistoryEntry::Notice(format!(
+                "{}'s fingerprint: {}",
+                name,
+                known_keys::display_fingerprint(fingerprint)
+            )),
+            (None, None) => HistoryEntry::Warning(format!("no key known for {}", name)),
+        };
+        self.push_history(entry);
+    }
+
     pub fn network_init(&mut self) {
         let pem = self
             .pkey
@@ -103,11 +1138,43 @@ impl<'a> App<'a> {
 
         self.queue_packet(ServerboundHandshake {
             public_key: String::from_utf8(pem).unwrap(),
+            name: self.name.clone(),
+            protocol_version: common::PROTOCOL_VERSION,
+            features: common::FEATURES.iter().map(|f| f.to_string()).collect(),
         });
     }
 
     pub fn draw(&mut self) {
-        let history_paragraph = Paragraph::new(self.history.join("\n"));
+        let room = &self.rooms[&self.room];
+        let faint_style = Style::default().fg(Color::DarkGray);
+        let mut lines: Vec<Line> = room.history.iter().map(HistoryEntry::to_line).collect();
+        if let Some(seen_by) = room.seen_by_notice() {
+            lines.push(Line::styled(seen_by, faint_style));
+        }
+        let line_count = lines.len();
+        let scroll = room.scroll;
+        let history_paragraph = Paragraph::new(lines);
+        let mut history_height = self.history_height;
+
+        let mut other_rooms: Vec<(&String, usize)> = self
+            .rooms
+            .iter()
+            .filter(|(name, _)| **name != self.room)
+            .map(|(name, room)| (name, room.unread(self.own_public_key())))
+            .filter(|(_, unread)| *unread > 0)
+            .collect();
+        other_rooms.sort();
+        let mut title = format!("#{}", self.room);
+        for (name, unread) in other_rooms {
+            title.push_str(&format!(" | #{} ({})", name, unread));
+        }
+        self.input
+            .set_block(Block::default().borders(Borders::ALL).title(title));
+
+        let members_paragraph = self.show_members.then(|| self.members_paragraph());
+        let typing_line = room
+            .typing_notice()
+            .map(|notice| Line::styled(notice, faint_style.add_modifier(Modifier::ITALIC)));
 
         self.terminal
             .draw(|frame| {
@@ -115,10 +1182,57 @@ impl<'a> App<'a> {
                 let textbox_rect = Rect::new(0, area.height - 3, area.width, 3);
                 frame.render_widget(&self.input, textbox_rect);
 
-                let history_rect = Rect::new(0, 0, area.width, area.height - 3);
-                frame.render_widget(&history_paragraph, history_rect);
+                let mut history_width = area.width;
+                if let Some(members_paragraph) = members_paragraph {
+                    let panel_width = MEMBER_PANEL_WIDTH.min(area.width);
+                    history_width -= panel_width;
+                    let panel_rect = Rect::new(history_width, 0, panel_width, area.height - 3);
+                    frame.render_widget(members_paragraph, panel_rect);
+                }
+
+                let mut history_bottom = area.height - 3;
+                if let Some(typing_line) = typing_line {
+                    history_bottom = history_bottom.saturating_sub(1);
+                    let typing_rect = Rect::new(0, history_bottom, history_width, 1);
+                    frame.render_widget(typing_line, typing_rect);
+                }
+
+                let history_rect = Rect::new(0, 0, history_width, history_bottom);
+                history_height = history_rect.height as usize;
+
+                // Keep the newest lines at the bottom of the pane
+                let top = line_count.saturating_sub(history_height + scroll);
+                frame.render_widget(history_paragraph.scroll((top as u16, 0)), history_rect);
             })
             .unwrap();
+
+        self.history_height = history_height;
+    }
+
+    /// Side panel listing who's online, toggled with F2
+    fn members_paragraph(&self) -> Paragraph<'static> {
+        let mut members: Vec<&Member> = self.online.values().collect();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let lines: Vec<Line> = members
+            .into_iter()
+            .map(|member| match member.devices {
+                1 => Line::raw(member.name.clone()),
+                devices => Line::raw(format!("{} ({})", member.name, devices)),
+            })
+            .collect();
+
+        Paragraph::new(lines).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Online ({})", self.online.len())),
+        )
+    }
+
+    fn push_history(&mut self, entry: HistoryEntry) {
+        if let Some(room) = self.rooms.get_mut(&self.room) {
+            room.history.push(entry);
+        }
     }
 
     fn create_input_textarea() -> TextArea<'a> {
@@ -128,47 +1242,132 @@ impl<'a> App<'a> {
         textarea
     }
 
-    fn send_message(&self, message: String) {
-        let mut signer = Signer::new(MessageDigest::sha256(), &self.pkey)
-            .expect("failed to create message signer");
-
-        signer
-            .update(message.as_bytes())
-            .expect("failed to update signer");
+    fn send_message(&mut self, message: String) {
+        let content = self.encrypt_for_peers(&message);
+        let signature = self.sign(content.as_bytes());
+        let correlation_id = self.next_correlation_id();
 
-        let signature = signer.sign_to_vec().expect("failed to sign message");
+        let entry = ChatMessage::outgoing(
+            self.name.clone(),
+            self.own_public_key().clone(),
+            message,
+            correlation_id.clone(),
+        );
+        self.push_history(HistoryEntry::Message(entry));
 
         self.queue_packet(MessagePacket {
-            content: message,
+            room: self.room.clone(),
+            content,
             signature,
+            sender: None,
+            sender_name: None,
+            id: None,
+            timestamp: None,
+            edited_at: None,
+            ttl: self.rooms[&self.room].ttl,
+            expires_at: None,
+            correlation_id: Some(correlation_id),
         });
     }
 
+    /// Encrypts text for every peer key the server sent, online or not
+    fn encrypt_for_peers(&self, text: &str) -> String {
+        let encrypted = crypto::encrypt(text.as_bytes(), self.peers.values())
+            .expect("failed to encrypt message");
+        serde_json::to_string(&encrypted).expect("failed to encode message")
+    }
+
+    fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.pkey)
+            .expect("failed to create message signer");
+
+        signer.update(data).expect("failed to update signer");
+        signer.sign_to_vec().expect("failed to sign message")
+    }
+
     fn queue_packet<P: Packet>(&self, packet: P) {
         self.outbound_message_send
-            .try_send(packet.network_encode())
+            .try_send(packet.encode(self.encoding))
             .unwrap();
     }
 }
 
+struct Args {
+    address: String,
+    identity: PathBuf,
+    name: String,
+}
+
+impl Args {
+    fn parse() -> Result<Args, String> {
+        let mut address = None;
+        let mut identity = None;
+        let mut name = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--identity" => match args.next() {
+                    Some(path) => identity = Some(PathBuf::from(path)),
+                    None => return Err("--identity requires a path".to_owned()),
+                },
+                "--name" => match args.next() {
+                    Some(n) => name = Some(n),
+                    None => return Err("--name requires a display name".to_owned()),
+                },
+                _ => address = Some(arg),
+            }
+        }
+
+        Ok(Args {
+            address: address.ok_or("specify a server address")?,
+            identity: identity.unwrap_or_else(identity::default_identity_path),
+            name: name
+                .or_else(|| std::env::var("USER").ok())
+                .unwrap_or_else(|| "anonymous".to_owned()),
+        })
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let address = match std::env::args().nth(1) {
-        Some(a) => a,
-        None => {
-            eprintln!("error: specify a server address");
+    let args = match Args::parse() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("error: {}", e);
             return;
         }
     };
 
-    let (socket, _) = connect_async(format!("ws://{}/", address))
+    let pkey = match identity::load_or_generate(&args.identity) {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!(
+                "error: couldn't load identity {}: {}",
+                args.identity.display(),
+                e
+            );
+            return;
+        }
+    };
+
+    let known_keys = match KnownKeys::load(&identity::config_dir().join("known_keys")) {
+        Ok(k) => k,
+        Err(e) => {
+            eprintln!("error: couldn't load known keys: {}", e);
+            return;
+        }
+    };
+
+    let (socket, _) = connect_async(format!("ws://{}/", args.address))
         .await
         .expect("can't connect");
 
     let (write, reader) = socket.split();
     let (outbound_msg_send, outbound_msg_recv) = mpsc::channel(8);
     let mut event_stream = EventStream::new();
-    let mut app = App::new(outbound_msg_send);
+    let mut prune_interval = tokio::time::interval(Duration::from_secs(1));
+    let mut app = App::new(outbound_msg_send, pkey, args.name, known_keys);
     tokio::spawn(send_to_server(write, outbound_msg_recv));
     tokio::spawn(receive_from_server(
         reader,
@@ -193,6 +1392,11 @@ async fn main() {
                     app.packet_received(m)
                 }
             }
+            _ = prune_interval.tick() => {
+                app.prune_expired();
+                app.update_typing();
+                app.mark_read_if_unread();
+            }
         }
     }
     ratatui::restore();
@@ -201,10 +1405,14 @@ async fn main() {
 
 async fn send_to_server(
     mut write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
-    mut outbound_messages: mpsc::Receiver<String>,
+    mut outbound_messages: mpsc::Receiver<Frame>,
 ) {
-    while let Some(msg) = outbound_messages.recv().await {
-        if let Err(e) = write.send(Message::Text(msg)).await {
+    while let Some(frame) = outbound_messages.recv().await {
+        let msg = match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Binary(bytes) => Message::Binary(bytes),
+        };
+        if let Err(e) = write.send(msg).await {
             eprintln!("failed to send message: {}", e);
         }
     }
@@ -212,23 +1420,23 @@ async fn send_to_server(
 
 async fn receive_from_server(
     mut read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    sending_channel: tokio::sync::mpsc::Sender<String>,
+    sending_channel: tokio::sync::mpsc::Sender<Frame>,
 ) -> Result<(), Error> {
     while let Some(msg) = read.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                sending_channel
-                    .send(text)
-                    .await
-                    .expect("Couldn't receive message from server.");
-            }
-            Ok(Message::Binary(bin)) => println!("Received binary data: {:?}", bin),
+        let frame = match msg {
+            Ok(Message::Text(text)) => Frame::Text(text),
+            Ok(Message::Binary(bytes)) => Frame::Binary(bytes),
             Err(e) => {
                 eprintln!("Error receiving message: {}", e);
                 break;
             }
             _ => panic!(),
-        }
+        };
+
+        sending_channel
+            .send(frame)
+            .await
+            .expect("Couldn't receive message from server.");
     }
     Ok(())
 }
@@ -1,10 +1,94 @@
-use std::io::ErrorKind;
+use std::fmt;
 
 use base64::prelude::BASE64_STANDARD;
 use base64::Engine;
-use serde::de::Unexpected;
+use serde::de::{DeserializeOwned, Visitor};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+/// Length of an RSA-2048 signature
+pub const SIGNATURE_LEN: usize = 256;
+
+/// Newest protocol version this build speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version this build can still decode. Version 1 is the
+/// format from before handshakes carried a version.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional capabilities a peer may or may not have, named in handshakes
+pub mod feature {
+    /// [`EditMessage`](crate::EditMessage) and
+    /// [`DeleteMessage`](crate::DeleteMessage)
+    pub const EDIT: &str = "edit";
+    /// [`MessagePacket::ttl`](crate::MessagePacket::ttl)
+    pub const DISAPPEARING: &str = "disappearing";
+    /// [`Encoding::Cbor`](crate::Encoding::Cbor) once the handshake is done
+    pub const CBOR: &str = "cbor";
+    /// [`RegisterName`](crate::RegisterName) and
+    /// [`LookupName`](crate::LookupName)
+    pub const NAMES: &str = "names";
+    /// [`Presence`](crate::Presence) and [`ListMembers`](crate::ListMembers)
+    pub const PRESENCE: &str = "presence";
+    /// [`TypingStarted`](crate::TypingStarted) and
+    /// [`TypingStopped`](crate::TypingStopped)
+    pub const TYPING: &str = "typing";
+    /// [`ReadUpTo`](crate::ReadUpTo) and
+    /// [`ReadPositions`](crate::ReadPositions)
+    pub const RECEIPTS: &str = "receipts";
+    /// [`QueueBatchEnd`](crate::QueueBatchEnd) and
+    /// [`QueueAck`](crate::QueueAck)
+    pub const QUEUE: &str = "queue";
+
+    /// Features version 1 peers have without listing them, since they predate
+    /// feature lists
+    pub const LEGACY: &[&str] = &[EDIT, DISAPPEARING];
+}
+
+/// Every feature this build supports
+pub const FEATURES: &[&str] = &[
+    feature::EDIT,
+    feature::DISAPPEARING,
+    feature::CBOR,
+    feature::NAMES,
+    feature::PRESENCE,
+    feature::TYPING,
+    feature::RECEIPTS,
+    feature::QUEUE,
+];
+
+/// Whether a peer that negotiated `protocol_version` and `features` supports
+/// `feature`
+pub fn supports(protocol_version: u32, features: &[String], feature: &str) -> bool {
+    if protocol_version < 2 {
+        return feature::LEGACY.contains(&feature);
+    }
+
+    features.iter().any(|f| f == feature)
+}
+
+/// Picks the version to talk to a peer that speaks up to `offered`, or `None`
+/// if there's no version both sides understand
+pub fn negotiate_version(offered: u32) -> Option<u32> {
+    let version = offered.min(PROTOCOL_VERSION);
+    (version >= MIN_PROTOCOL_VERSION).then_some(version)
+}
+
+/// The features in `offered` that this build also supports
+pub fn common_features(offered: &[String]) -> Vec<String> {
+    offered
+        .iter()
+        .filter(|f| FEATURES.contains(&f.as_str()))
+        .cloned()
+        .collect()
+}
+
+/// How often a client repeats [`TypingStarted`] while its user keeps typing.
+/// Receivers drop an indicator that hasn't been repeated for twice as long.
+pub const TYPING_REFRESH_SECS: u64 = 3;
+
+fn legacy_protocol_version() -> u32 {
+    1
+}
+
 pub trait Packet: Serialize {
     const ID: &'static str;
 
@@ -12,65 +96,684 @@ pub trait Packet: Serialize {
         let packet_json = serde_json::to_string(&self).expect("couldn't encode packet to json");
         format!("{}|{}", Self::ID, packet_json)
     }
+
+    /// Like [`Packet::network_encode`], but with the packet as CBOR
+    fn network_encode_binary(&self) -> Vec<u8> {
+        let mut frame = format!("{}|", Self::ID).into_bytes();
+        ciborium::into_writer(&self, &mut frame).expect("couldn't encode packet to cbor");
+        frame
+    }
+
+    fn encode(&self, encoding: Encoding) -> Frame {
+        match encoding {
+            Encoding::Json => Frame::Text(self.network_encode()),
+            Encoding::Cbor => Frame::Binary(self.network_encode_binary()),
+        }
+    }
+
+    fn decode(body: Body<'_>) -> Result<Self, DecodeError>
+    where
+        Self: DeserializeOwned,
+    {
+        match body {
+            Body::Json(json_data) => decode_packet(json_data),
+            Body::Cbor(cbor_data) => {
+                ciborium::from_reader(cbor_data).map_err(DecodeError::InvalidCbor)
+            }
+        }
+    }
+}
+
+/// How packets are written to the socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `id|json` in text frames. Every peer understands it.
+    Json,
+    /// `id|cbor` in binary frames. Only used once both sides negotiated
+    /// [`feature::CBOR`].
+    Cbor,
+}
+
+/// A WebSocket data frame holding one packet
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    /// Splits the frame into the packet ID and its body
+    pub fn split(&self) -> Result<(&str, Body<'_>), DecodeError> {
+        match self {
+            Frame::Text(raw) => {
+                network_decode(raw).map(|(id, json_data)| (id, Body::Json(json_data)))
+            }
+            Frame::Binary(raw) => {
+                network_decode_binary(raw).map(|(id, cbor_data)| (id, Body::Cbor(cbor_data)))
+            }
+        }
+    }
+}
+
+/// The encoded packet in a [`Frame`], after its ID
+#[derive(Debug, Clone, Copy)]
+pub enum Body<'a> {
+    Json(&'a str),
+    Cbor(&'a [u8]),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessagePacket {
+    pub room: String,
+    /// JSON-encoded [`EncryptedContent`]. The server only ever sees (and signs
+    /// off on) this ciphertext envelope.
     pub content: String,
+    /// Signature of `content`, or of [`EditMessage::signed_data`] once the
+    /// message has been edited
     #[serde(
-        serialize_with = "serialize_as_base64",
-        deserialize_with = "deserialize_from_base64"
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_signature"
     )]
     pub signature: Vec<u8>,
+    /// PEM public key of the author. Filled in by the server when relaying;
+    /// whatever a client puts here is ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    /// Display name of the author, filled in by the server like `sender`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+    /// Time-ordered UUID the server assigns when storing the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// When the server received the message, in milliseconds since the Unix
+    /// epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+    /// When the message was last edited, in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<i64>,
+    /// Seconds the sender wants the message kept for. The server may shorten
+    /// it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u32>,
+    /// When the message disappears, in milliseconds since the Unix epoch.
+    /// Filled in by the server from `ttl`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    /// See [`Ack::correlation_id`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+/// A message body encrypted with a random AES-256-GCM key. That key is wrapped
+/// with RSA-OAEP once per recipient so only they can read the message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedContent {
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_bytes"
+    )]
+    pub iv: Vec<u8>,
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_bytes"
+    )]
+    pub ciphertext: Vec<u8>,
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_bytes"
+    )]
+    pub tag: Vec<u8>,
+    pub keys: Vec<WrappedKey>,
 }
 
-fn serialize_as_base64<S>(val: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WrappedKey {
+    /// Fingerprint of the public key `key` was encrypted with
+    pub recipient: String,
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_bytes"
+    )]
+    pub key: Vec<u8>,
+}
+
+/// Base64 in text formats like JSON, raw bytes in binary ones like CBOR
+fn serialize_bytes<S>(val: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
-    let encoded = BASE64_STANDARD.encode(val);
-    serializer.serialize_str(&encoded)
+    if serializer.is_human_readable() {
+        let encoded = BASE64_STANDARD.encode(val);
+        serializer.serialize_str(&encoded)
+    } else {
+        serializer.serialize_bytes(val)
+    }
+}
+
+fn deserialize_signature<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let bytes = deserialize_bytes(deserializer)?;
+
+    if bytes.len() != SIGNATURE_LEN {
+        return Err(serde::de::Error::invalid_length(
+            bytes.len(),
+            &"a 256-byte signature",
+        ));
+    }
+
+    Ok(bytes)
 }
 
-fn deserialize_from_base64<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+fn deserialize_bytes<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(deserializer)?;
-    let bytes = BASE64_STANDARD
-        .decode(&s)
-        .map_err(serde::de::Error::custom)?;
+    if deserializer.is_human_readable() {
+        let s = String::deserialize(deserializer)?;
+        BASE64_STANDARD.decode(&s).map_err(serde::de::Error::custom)
+    } else {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
 
-    bytes.as_slice()[..256].try_into().map_err(|_| {
-        serde::de::Error::invalid_value(Unexpected::Str(&s), &"256-byte base64 string")
-    })
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a byte string")
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(v)
+    }
 }
 
 impl Packet for MessagePacket {
     const ID: &'static str = "message";
 }
 
-pub fn network_decode<'a>(raw: &'a str) -> Result<(&'a str, &'a str), std::io::Error> {
-    let mut parts = raw.splitn(2, "|");
-    let id = parts.next().unwrap();
-    let json_data = match parts.next() {
-        Some(json) => json,
-        None => {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidData,
-                "packet didn't have '|' followed by JSON-encoded data",
-            ))
+/// A message delivered only to the live connections of one key. If the
+/// recipient is offline, the server stores it until they next connect.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirectMessagePacket {
+    /// Fingerprint of the recipient's public key, see [`key_fingerprint`]
+    pub recipient: String,
+    /// JSON-encoded [`EncryptedContent`], like [`MessagePacket::content`]
+    pub content: String,
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_signature"
+    )]
+    pub signature: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+    /// See [`Ack::correlation_id`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Packet for DirectMessagePacket {
+    const ID: &'static str = "direct_message";
+}
+
+/// Hex-encoded SHA-256 of the DER encoding of a public key
+pub fn key_fingerprint(public_key_der: &[u8]) -> String {
+    openssl::sha::sha256(public_key_der)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Why a frame couldn't be turned into a packet
+#[derive(Debug)]
+pub enum DecodeError {
+    /// There was no `|` between the packet ID and its JSON
+    MissingSeparator,
+    UnknownPacket(String),
+    InvalidJson(serde_json::Error),
+    InvalidCbor(ciborium::de::Error<std::io::Error>),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::MissingSeparator => {
+                write!(f, "packet didn't have '|' followed by JSON-encoded data")
+            }
+            DecodeError::UnknownPacket(id) => write!(f, "unexpected packet ID {}", id),
+            DecodeError::InvalidJson(e) => write!(f, "invalid packet data: {}", e),
+            DecodeError::InvalidCbor(e) => write!(f, "invalid packet data: {}", e),
         }
-    };
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::InvalidJson(e) => Some(e),
+            DecodeError::InvalidCbor(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
-    Ok((id, json_data))
+/// Splits a frame made by [`Packet::network_encode`] into the packet ID and
+/// its JSON
+pub fn network_decode(raw: &str) -> Result<(&str, &str), DecodeError> {
+    raw.split_once('|').ok_or(DecodeError::MissingSeparator)
+}
+
+/// Splits a frame made by [`Packet::network_encode_binary`] into the packet ID
+/// and its CBOR
+pub fn network_decode_binary(raw: &[u8]) -> Result<(&str, &[u8]), DecodeError> {
+    let separator = raw
+        .iter()
+        .position(|b| *b == b'|')
+        .ok_or(DecodeError::MissingSeparator)?;
+
+    let id = &raw[..separator];
+    let id = std::str::from_utf8(id)
+        .map_err(|_| DecodeError::UnknownPacket(String::from_utf8_lossy(id).into_owned()))?;
+    Ok((id, &raw[separator + 1..]))
+}
+
+/// Parses the JSON half of a frame split by [`network_decode`]
+pub fn decode_packet<P: DeserializeOwned>(json_data: &str) -> Result<P, DecodeError> {
+    serde_json::from_str(json_data).map_err(DecodeError::InvalidJson)
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ServerboundHandshake {
     pub public_key: String,
+    /// Display name shown to other users next to this key
+    pub name: String,
+    /// Newest version the client speaks. Missing from version 1 clients.
+    #[serde(default = "legacy_protocol_version")]
+    pub protocol_version: u32,
+    /// Names from [`feature`] the client supports
+    #[serde(default)]
+    pub features: Vec<String>,
 }
 
 impl Packet for ServerboundHandshake {
     const ID: &'static str = "serverbound_handshake";
 }
+
+/// Sent to clients so they know whose public keys to encrypt messages for
+#[derive(Serialize, Deserialize)]
+pub struct PeerKeyPacket {
+    pub public_key: String,
+    pub name: String,
+}
+
+impl Packet for PeerKeyPacket {
+    const ID: &'static str = "peer_key";
+}
+
+/// Sent in reply to a [`ServerboundHandshake`]. The client proves it holds the
+/// private key for the public key it sent by signing `nonce`.
+#[derive(Serialize, Deserialize)]
+pub struct ClientboundChallenge {
+    pub nonce: String,
+    /// Version the server chose for the rest of the connection. Servers that
+    /// predate versioning leave it out.
+    #[serde(default = "legacy_protocol_version")]
+    pub protocol_version: u32,
+    /// Features both sides support
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl Packet for ClientboundChallenge {
+    const ID: &'static str = "clientbound_challenge";
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ServerboundChallengeResponse {
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_signature"
+    )]
+    pub signature: Vec<u8>,
+}
+
+impl Packet for ServerboundChallengeResponse {
+    const ID: &'static str = "serverbound_challenge_response";
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JoinRoom {
+    pub room: String,
+}
+
+impl Packet for JoinRoom {
+    const ID: &'static str = "join_room";
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LeaveRoom {
+    pub room: String,
+}
+
+impl Packet for LeaveRoom {
+    const ID: &'static str = "leave_room";
+}
+
+/// Asks for a page of a joined room's history
+#[derive(Serialize, Deserialize)]
+pub struct HistoryRequest {
+    pub room: String,
+    /// [`HistoryPage::cursor`] of the previous response, or `None` for the
+    /// newest messages
+    #[serde(default)]
+    pub cursor: Option<String>,
+    pub limit: u32,
+}
+
+impl Packet for HistoryRequest {
+    const ID: &'static str = "history_request";
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub room: String,
+    /// Oldest first
+    pub messages: Vec<MessagePacket>,
+    /// Cursor for the next older page, `None` once the start of the room is
+    /// reached
+    pub cursor: Option<String>,
+}
+
+impl Packet for HistoryPage {
+    const ID: &'static str = "history_page";
+}
+
+/// Replaces the content of a room message. Only accepted from the key that
+/// sent the original.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EditMessage {
+    pub room: String,
+    /// [`MessagePacket::id`] of the message to edit
+    pub id: String,
+    /// New JSON-encoded [`EncryptedContent`]
+    pub content: String,
+    /// Signature of [`EditMessage::signed_data`]
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_signature"
+    )]
+    pub signature: Vec<u8>,
+    /// See [`Ack::correlation_id`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Packet for EditMessage {
+    const ID: &'static str = "edit_message";
+}
+
+impl EditMessage {
+    /// What the author signs. Covering the room and ID keeps the signature
+    /// from being replayed as an edit of another message.
+    pub fn signed_data(room: &str, id: &str, content: &str) -> Vec<u8> {
+        format!("edit\0{}\0{}\0{}", room, id, content).into_bytes()
+    }
+}
+
+/// Removes a room message. Only accepted from the key that sent it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DeleteMessage {
+    pub room: String,
+    /// [`MessagePacket::id`] of the message to delete
+    pub id: String,
+    /// Signature of [`DeleteMessage::signed_data`]
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_signature"
+    )]
+    pub signature: Vec<u8>,
+    /// See [`Ack::correlation_id`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Packet for DeleteMessage {
+    const ID: &'static str = "delete_message";
+}
+
+impl DeleteMessage {
+    /// What the author signs, covering the room so the signature can't be
+    /// replayed elsewhere
+    pub fn signed_data(room: &str, id: &str) -> Vec<u8> {
+        format!("delete\0{}\0{}", room, id).into_bytes()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The packet needs a completed handshake
+    NoHandshake,
+    HandshakeInProgress,
+    /// No protocol version is understood by both sides
+    UnsupportedVersion,
+    InvalidKey,
+    InvalidName,
+    BadSignature,
+    InvalidRoom,
+    NotInRoom,
+    InvalidMessageId,
+    UnknownMessage,
+    /// Only the author of a message can change it
+    NotAuthor,
+    /// The name is registered to another key
+    NameTaken,
+    /// The frame couldn't be decoded
+    MalformedPacket,
+    UnknownPacket,
+    /// The server failed to handle an otherwise valid packet
+    Internal,
+}
+
+/// Tells a client the server rejected one of its packets
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorPacket {
+    pub code: ErrorCode,
+    /// Human-readable detail
+    pub message: String,
+    /// Correlation ID of the rejected packet, if it had one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Packet for ErrorPacket {
+    const ID: &'static str = "error";
+}
+
+/// Tells a client the server accepted a packet that had a correlation ID
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ack {
+    /// Chosen by the client on the packet being answered and echoed here or in
+    /// the [`ErrorPacket`] rejecting it. Never relayed to other clients.
+    pub correlation_id: String,
+    /// ID assigned to a new room message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+impl Packet for Ack {
+    const ID: &'static str = "ack";
+}
+
+/// Registers a display name to the sender's key, releasing any name it had
+/// before. Names are unique; the server refuses one registered to another key
+/// with [`ErrorCode::NameTaken`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegisterName {
+    pub name: String,
+    /// See [`Ack::correlation_id`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+}
+
+impl Packet for RegisterName {
+    const ID: &'static str = "register_name";
+}
+
+/// Asks which key a name is registered to. Answered with a [`NameInfo`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LookupName {
+    pub name: String,
+}
+
+impl Packet for LookupName {
+    const ID: &'static str = "lookup_name";
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NameInfo {
+    pub name: String,
+    /// PEM public key the name is registered to, or `None` if it's free
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+impl Packet for NameInfo {
+    const ID: &'static str = "name_info";
+}
+
+/// Tells clients a device using a key connected or disconnected
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Presence {
+    pub public_key: String,
+    pub name: String,
+    /// How many connections are using the key now. Zero means it went
+    /// offline.
+    pub devices: u32,
+}
+
+impl Packet for Presence {
+    const ID: &'static str = "presence";
+}
+
+/// Asks for everyone online. Answered with a [`MemberList`].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListMembers {}
+
+impl Packet for ListMembers {
+    const ID: &'static str = "list_members";
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemberList {
+    pub members: Vec<Member>,
+}
+
+impl Packet for MemberList {
+    const ID: &'static str = "member_list";
+}
+
+/// A key with at least one connected device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Member {
+    pub public_key: String,
+    pub name: String,
+    /// How many connections are using the key
+    pub devices: u32,
+}
+
+/// Someone has text in their input box for a room. Relayed to the room but
+/// never stored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TypingStarted {
+    pub room: String,
+    /// Fingerprint of the typist's key, filled in by the server when relaying
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Filled in by the server like `fingerprint`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+}
+
+impl Packet for TypingStarted {
+    const ID: &'static str = "typing_started";
+}
+
+/// Someone cleared their input box or sent what they typed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TypingStopped {
+    pub room: String,
+    /// Filled in by the server like [`TypingStarted::fingerprint`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+impl Packet for TypingStopped {
+    const ID: &'static str = "typing_stopped";
+}
+
+/// Marks a room as read up to and including a message. The server keeps the
+/// newest one per key and relays it to the room.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadUpTo {
+    pub room: String,
+    /// [`MessagePacket::id`] of the newest message read
+    pub message_id: String,
+    /// Fingerprint of the reader's key, filled in by the server when relaying
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Filled in by the server like `fingerprint`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_name: Option<String>,
+}
+
+impl Packet for ReadUpTo {
+    const ID: &'static str = "read_up_to";
+}
+
+/// How far everyone has read a room, sent after joining it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadPositions {
+    pub room: String,
+    pub positions: Vec<ReadUpTo>,
+}
+
+impl Packet for ReadPositions {
+    const ID: &'static str = "read_positions";
+}
+
+/// Follows a batch of packets that were queued while the client's key was
+/// offline. The client answers with [`QueueAck`] once it has handled them.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueBatchEnd {
+    /// Position of the last packet in the batch
+    pub up_to: String,
+    /// Whether more packets are waiting after this batch
+    pub more: bool,
+}
+
+impl Packet for QueueBatchEnd {
+    const ID: &'static str = "queue_batch_end";
+}
+
+/// Removes everything up to and including [`QueueBatchEnd::up_to`] from the
+/// queue and asks for the next batch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueAck {
+    pub up_to: String,
+}
+
+impl Packet for QueueAck {
+    const ID: &'static str = "queue_ack";
+}
@@ -0,0 +1,121 @@
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use common::{
+    decode_packet, network_decode, network_decode_binary, Body, DecodeError, DirectMessagePacket,
+    Encoding, Frame, HistoryRequest, MessagePacket, Packet, ServerboundChallengeResponse,
+    ServerboundHandshake,
+};
+use proptest::prelude::*;
+
+fn message(room: String, content: String, signature: Vec<u8>) -> MessagePacket {
+    MessagePacket {
+        room,
+        content,
+        signature,
+        sender: None,
+        sender_name: None,
+        id: None,
+        timestamp: None,
+        edited_at: None,
+        ttl: None,
+        expires_at: None,
+        correlation_id: None,
+    }
+}
+
+proptest! {
+    #[test]
+    fn network_decode_never_panics(raw in any::<String>()) {
+        match network_decode(&raw) {
+            Ok((id, json_data)) => {
+                prop_assert!(!id.contains('|'));
+                prop_assert_eq!(format!("{}|{}", id, json_data), raw);
+            }
+            Err(e) => {
+                prop_assert!(matches!(e, DecodeError::MissingSeparator));
+                prop_assert!(!raw.contains('|'));
+            }
+        }
+    }
+
+    #[test]
+    fn decoding_arbitrary_json_never_panics(json_data in any::<String>()) {
+        let _ = decode_packet::<MessagePacket>(&json_data);
+        let _ = decode_packet::<DirectMessagePacket>(&json_data);
+        let _ = decode_packet::<ServerboundHandshake>(&json_data);
+        let _ = decode_packet::<ServerboundChallengeResponse>(&json_data);
+        let _ = decode_packet::<HistoryRequest>(&json_data);
+    }
+
+    #[test]
+    fn message_round_trips(
+        room in any::<String>(),
+        content in any::<String>(),
+        signature in prop::collection::vec(any::<u8>(), 256),
+    ) {
+        let encoded = message(room.clone(), content.clone(), signature.clone()).network_encode();
+
+        let (id, json_data) = network_decode(&encoded).unwrap();
+        prop_assert_eq!(id, MessagePacket::ID);
+
+        let decoded: MessagePacket = decode_packet(json_data).unwrap();
+        prop_assert_eq!(decoded.room, room);
+        prop_assert_eq!(decoded.content, content);
+        prop_assert_eq!(decoded.signature, signature);
+    }
+
+    #[test]
+    fn signatures_must_be_256_bytes(
+        signature in prop::collection::vec(any::<u8>(), 0..600),
+    ) {
+        let json_data = format!(
+            r#"{{"signature":"{}"}}"#,
+            BASE64_STANDARD.encode(&signature)
+        );
+
+        let decoded = decode_packet::<ServerboundChallengeResponse>(&json_data);
+        prop_assert_eq!(decoded.is_ok(), signature.len() == 256);
+    }
+
+    #[test]
+    fn invalid_base64_is_an_error(signature in "[^A-Za-z0-9+/=]+") {
+        let json_data = serde_json::json!({ "signature": signature }).to_string();
+        prop_assert!(decode_packet::<ServerboundChallengeResponse>(&json_data).is_err());
+    }
+
+    #[test]
+    fn binary_decode_never_panics(raw in prop::collection::vec(any::<u8>(), 0..512)) {
+        if let Ok((id, cbor_data)) = network_decode_binary(&raw) {
+            let _ = MessagePacket::decode(Body::Cbor(cbor_data));
+            let _ = HistoryRequest::decode(Body::Cbor(cbor_data));
+            prop_assert_eq!(id.len() + 1 + cbor_data.len(), raw.len());
+        }
+    }
+
+    #[test]
+    fn message_round_trips_as_cbor(
+        room in any::<String>(),
+        content in any::<String>(),
+        signature in prop::collection::vec(any::<u8>(), 256),
+    ) {
+        let frame = message(room.clone(), content.clone(), signature.clone()).encode(Encoding::Cbor);
+        prop_assert!(matches!(frame, Frame::Binary(_)));
+
+        let (id, body) = frame.split().unwrap();
+        prop_assert_eq!(id, MessagePacket::ID);
+
+        let decoded = MessagePacket::decode(body).unwrap();
+        prop_assert_eq!(decoded.room, room);
+        prop_assert_eq!(decoded.content, content);
+        prop_assert_eq!(decoded.signature, signature);
+    }
+}
+
+#[test]
+fn cbor_is_smaller_than_json() {
+    let packet = message("general".to_owned(), "{}".to_owned(), vec![7; 256]);
+
+    let json = packet.network_encode();
+    let cbor = packet.network_encode_binary();
+    assert!(cbor.len() + 80 < json.len());
+}
@@ -0,0 +1,49 @@
+use common::{
+    decode_packet, feature, negotiate_version, network_decode, supports, ClientboundChallenge,
+    ServerboundHandshake, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+#[test]
+fn version_1_handshake_still_decodes() {
+    let raw = r#"serverbound_handshake|{"public_key":"key","name":"alice"}"#;
+
+    let (_, json_data) = network_decode(raw).unwrap();
+    let handshake: ServerboundHandshake = decode_packet(json_data).unwrap();
+    assert_eq!(handshake.protocol_version, 1);
+    assert!(handshake.features.is_empty());
+}
+
+#[test]
+fn version_1_challenge_still_decodes() {
+    let challenge: ClientboundChallenge = decode_packet(r#"{"nonce":"abc"}"#).unwrap();
+    assert_eq!(challenge.protocol_version, 1);
+}
+
+#[test]
+fn negotiates_highest_shared_version() {
+    assert_eq!(
+        negotiate_version(PROTOCOL_VERSION + 5),
+        Some(PROTOCOL_VERSION)
+    );
+    assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+    assert_eq!(
+        negotiate_version(MIN_PROTOCOL_VERSION),
+        Some(MIN_PROTOCOL_VERSION)
+    );
+    assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+}
+
+#[test]
+fn version_1_peers_only_have_legacy_features() {
+    for legacy in feature::LEGACY {
+        assert!(supports(1, &[], legacy));
+    }
+    assert!(!supports(1, &[], "added later"));
+}
+
+#[test]
+fn version_2_peers_have_the_features_they_list() {
+    let features = vec![feature::EDIT.to_owned()];
+    assert!(supports(2, &features, feature::EDIT));
+    assert!(!supports(2, &features, feature::DISAPPEARING));
+}
@@ -1,15 +1,53 @@
+use async_trait::async_trait;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use common::SIGNATURE_LEN;
 use futures::TryStreamExt;
-use rand::Rng;
-use scylla::batch::Batch;
-use scylla::{FromRow, Session, SessionBuilder};
-use serde::{Deserialize, Serialize};
+use scylla::frame::value::CqlTimestamp;
+use scylla::query::Query;
+use scylla::statement::{PagingState, PagingStateResponse};
+use scylla::QueryResult;
+use scylla::{Session, SessionBuilder};
 use std::error::Error;
+use uuid::{Builder, Uuid};
 
-#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
-pub struct Message {
-    pub content: String,
-    pub signature: Vec<u8>,
-}
+use crate::migrations::{BOOTSTRAP, MIGRATIONS, ROOM_MESSAGES};
+use crate::store::{
+    DirectMessage, InvalidCursor, Message, MessageStore, Page, Queued, ReadPosition,
+    RegisteredName, StoreError, Subscriber,
+};
+
+/// A `direct_messages` row with the clustering columns needed to delete it
+type StoredDirectMessage = (
+    CqlTimestamp,
+    i64,
+    String,
+    String,
+    Vec<u8>,
+    Option<String>,
+    Option<String>,
+);
+
+/// A row of the `messages` table that `room_messages` replaced, with the TTL
+/// it has left. Rows from before rooms existed have no room, and messages
+/// stored with a TTL have no signature.
+type LegacyMessage = (
+    Option<String>,
+    CqlTimestamp,
+    i64,
+    Option<String>,
+    Option<Vec<u8>>,
+    Option<String>,
+    Option<String>,
+    Option<i32>,
+);
+
+/// Room that history from before rooms existed is copied into, the one clients
+/// open first
+const LEGACY_ROOM: &str = "general";
+
+/// Columns of `room_messages` in the order [`Message`] reads them
+const MESSAGE_COLUMNS: &str = "room, id, toUnixTimestamp(timestamp), message, signature, sender, sender_name, toUnixTimestamp(edited_at), toUnixTimestamp(expires_at)";
 
 pub struct Cassandra {
     session: Session,
@@ -23,13 +61,137 @@ impl Cassandra {
         Ok(Cassandra { session })
     }
 
+    /// Applies every migration newer than the recorded schema version
+    pub async fn migrate(&self) -> Result<(), StoreError> {
+        for statement in BOOTSTRAP {
+            self.session.query_unpaged(*statement, &[]).await?;
+        }
+
+        let versions: Vec<(i32,)> = self
+            .session
+            .query_iter("SELECT version FROM eteedir.schema_version", &[])
+            .await?
+            .into_typed::<(i32,)>()
+            .try_collect()
+            .await?;
+        let current = versions.into_iter().map(|(v,)| v).max().unwrap_or(0);
+
+        for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+            println!("applying schema migration {}", migration.version);
+
+            for statement in migration.statements {
+                self.session.query_unpaged(*statement, &[]).await?;
+            }
+
+            if migration.version == ROOM_MESSAGES {
+                self.copy_legacy_messages().await?;
+            }
+
+            self.session
+                .query_unpaged(
+                    "INSERT INTO eteedir.schema_version (version, applied_at) VALUES (?, ToTimeStamp(NOW()))",
+                    (migration.version,),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Copies the history in the old `messages` table into `room_messages`.
+    /// Each row's UUIDv7 is built from its timestamp and old ID rather than
+    /// drawn at random, so running this again after a failure overwrites the
+    /// rows it already copied instead of duplicating them. Rows without a
+    /// signature are dropped, since clients reject a history page holding one.
+    async fn copy_legacy_messages(&self) -> Result<(), StoreError> {
+        let mut rows = self
+            .session
+            .query_iter(
+                "SELECT room, timestamp, id, message, signature, sender, sender_name, TTL(message) FROM eteedir.messages",
+                &[],
+            )
+            .await?
+            .into_typed::<LegacyMessage>();
+
+        let (mut copied, mut skipped) = (0, 0);
+        while let Some((room, timestamp, id, message, signature, sender, sender_name, ttl)) =
+            rows.try_next().await?
+        {
+            let Some(signature) = signature.filter(|s| s.len() == SIGNATURE_LEN) else {
+                skipped += 1;
+                continue;
+            };
+
+            let mut id_bytes = [0; 10];
+            id_bytes[..8].copy_from_slice(&id.to_be_bytes());
+            let id = Builder::from_unix_timestamp_millis(timestamp.0 as u64, &id_bytes).into_uuid();
+
+            self.session
+                .query_unpaged(
+                    "INSERT INTO eteedir.room_messages (room, id, timestamp, message, signature, sender, sender_name) VALUES(?, ?, ?, ?, ?, ?, ?) USING TTL ?",
+                    (
+                        room.as_deref().unwrap_or(LEGACY_ROOM),
+                        id,
+                        timestamp,
+                        message.unwrap_or_default(),
+                        signature,
+                        sender,
+                        sender_name,
+                        // A TTL of 0 keeps the row forever
+                        ttl.unwrap_or(0),
+                    ),
+                )
+                .await?;
+            copied += 1;
+        }
+
+        println!(
+            "copied {} messages into room_messages, dropped {} without a signature",
+            copied, skipped
+        );
+        Ok(())
+    }
+
+    /// Moves a stored read position forward, or returns `false` if there's
+    /// none yet or it's already at or past `position`
+    async fn advance_existing_read_position(
+        &self,
+        position: &ReadPosition,
+    ) -> Result<bool, StoreError> {
+        let result = self
+            .session
+            .query_unpaged(
+                "UPDATE eteedir.read_positions SET message_id = ?, name = ? WHERE room = ? AND fingerprint = ? IF message_id < ?",
+                (
+                    position.message_id,
+                    &position.name,
+                    &position.room,
+                    &position.fingerprint,
+                    position.message_id,
+                ),
+            )
+            .await?;
+
+        applied(result)
+    }
+}
+
+#[async_trait]
+impl MessageStore for Cassandra {
     // IF
-    pub async fn insert_message(&self, message: &Message) -> Result<(), Box<dyn Error>> {
-        let id = rand::thread_rng().gen::<i64>();
+    async fn insert_message(&self, message: &Message) -> Result<(), StoreError> {
         self.session
             .query_unpaged(
-                "INSERT INTO eteedir.messages (id, message, signature, timestamp) VALUES(?, ?, ?, ToTimeStamp(NOW())) IF NOT EXISTS",
-                (id, message.content.clone(), message.signature.clone()),
+                "INSERT INTO eteedir.room_messages (room, id, timestamp, message, signature, sender, sender_name) VALUES(?, ?, ?, ?, ?, ?, ?) IF NOT EXISTS",
+                (
+                    message.room.clone(),
+                    message.id,
+                    CqlTimestamp(message.timestamp),
+                    message.content.clone(),
+                    message.signature.clone(),
+                    message.sender.clone(),
+                    message.sender_name.clone(),
+                ),
             )
             .await?;
 
@@ -37,126 +199,360 @@ impl Cassandra {
     }
 
     // TTL(Time to Live)
-    pub async fn insert_message_ttl(
+    async fn insert_message_ttl(&self, message: &Message, seconds: i32) -> Result<(), StoreError> {
+        self.session
+            .query_unpaged(
+                "INSERT INTO eteedir.room_messages (room, id, timestamp, message, signature, sender, sender_name, expires_at) VALUES(?, ?, ?, ?, ?, ?, ?, ?) USING TTL ?",
+                (
+                    message.room.clone(),
+                    message.id,
+                    CqlTimestamp(message.timestamp),
+                    message.content.clone(),
+                    message.signature.clone(),
+                    message.sender.clone(),
+                    message.sender_name.clone(),
+                    message.expires_at.map(CqlTimestamp),
+                    seconds,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn read_page(
+        &self,
+        room: &str,
+        cursor: Option<&str>,
+        limit: i32,
+    ) -> Result<Page, StoreError> {
+        let paging_state = match cursor {
+            Some(c) => PagingState::new_from_raw_bytes(
+                BASE64_STANDARD.decode(c).map_err(|_| InvalidCursor)?,
+            ),
+            None => PagingState::start(),
+        };
+
+        let mut query = Query::new(format!(
+            "SELECT {} FROM eteedir.room_messages WHERE room = ? ORDER BY id DESC",
+            MESSAGE_COLUMNS
+        ));
+        query.set_page_size(limit);
+
+        let (result, paging_state_response) = self
+            .session
+            .query_single_page(query, (room,), paging_state)
+            .await?;
+
+        let mut messages = result
+            .rows_typed::<Message>()?
+            .collect::<Result<Vec<_>, _>>()?;
+        messages.reverse();
+
+        let cursor = match paging_state_response {
+            PagingStateResponse::HasMorePages { state } => state
+                .as_bytes_slice()
+                .map(|bytes| BASE64_STANDARD.encode(bytes)),
+            PagingStateResponse::NoMorePages => None,
+        };
+
+        Ok(Page { messages, cursor })
+    }
+
+    async fn get_message(&self, room: &str, id: Uuid) -> Result<Option<Message>, StoreError> {
+        let message = self
+            .session
+            .query_unpaged(
+                format!(
+                    "SELECT {} FROM eteedir.room_messages WHERE room = ? AND id = ?",
+                    MESSAGE_COLUMNS
+                ),
+                (room, id),
+            )
+            .await?
+            .maybe_first_row_typed::<Message>()?;
+
+        Ok(message)
+    }
+
+    // IF EXISTS keeps an edit racing a delete from recreating the row
+    async fn update_message(
         &self,
-        message: &Message,
-        seconds: i32,
-    ) -> Result<(), Box<dyn Error>> {
-        let id = rand::thread_rng().gen::<i64>();
+        room: &str,
+        id: Uuid,
+        content: String,
+        signature: Vec<u8>,
+        edited_at: i64,
+    ) -> Result<(), StoreError> {
+        // Cells written by an UPDATE don't inherit the row's TTL, so carry
+        // over what's left of it or the edit would outlive the message
+        let (remaining,) = self
+            .session
+            .query_unpaged(
+                "SELECT TTL(message) FROM eteedir.room_messages WHERE room = ? AND id = ?",
+                (room, id),
+            )
+            .await?
+            .maybe_first_row_typed::<(Option<i32>,)>()?
+            .unwrap_or((None,));
+
+        self.session
+            .query_unpaged(
+                "UPDATE eteedir.room_messages USING TTL ? SET message = (?), signature = (?), edited_at = (?) WHERE room = (?) AND id = (?) IF EXISTS",
+                (
+                    remaining.unwrap_or(0),
+                    content,
+                    signature,
+                    CqlTimestamp(edited_at),
+                    room,
+                    id,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn delete_message(&self, room: &str, id: Uuid) -> Result<(), StoreError> {
         self.session
             .query_unpaged(
-                "INSERT INTO eteedir.messages (id, message, timestamp) VALUES(?, ?, ToTimeStamp(NOW())) USING TTL ?",
-                (id, message.content.clone(), seconds,),
+                "DELETE FROM eteedir.room_messages WHERE room = (?) AND id = (?)",
+                (room, id),
             )
             .await?;
 
         Ok(())
     }
 
-    pub async fn read_messages(&self) -> Result<Vec<Message>, Box<dyn Error>> {
-        let messages = self
+    /// Removes and returns every direct message stored for `recipient`
+    async fn take_direct_messages(
+        &self,
+        recipient: &str,
+    ) -> Result<Vec<DirectMessage>, StoreError> {
+        let rows: Vec<StoredDirectMessage> = self
             .session
-            .query_iter("SELECT message, signature FROM eteedir.messages", &[])
+            .query_iter(
+                "SELECT timestamp, id, recipient, message, signature, sender, sender_name FROM eteedir.direct_messages WHERE recipient = ? ORDER BY timestamp",
+                (recipient,),
+            )
             .await?
-            .into_typed::<Message>();
+            .into_typed::<StoredDirectMessage>()
+            .try_collect()
+            .await?;
 
-        let vec: Vec<Message> = messages.try_collect().await?;
+        // Only the rows that were read are deleted, so one stored in the
+        // meantime is left for next time
+        let mut messages = Vec::with_capacity(rows.len());
+        for (timestamp, id, recipient, content, signature, sender, sender_name) in rows {
+            self.session
+                .query_unpaged(
+                    "DELETE FROM eteedir.direct_messages WHERE recipient = ? AND timestamp = ? AND id = ?",
+                    (&recipient, timestamp, id),
+                )
+                .await?;
 
-        Ok(vec)
+            messages.push(DirectMessage {
+                recipient,
+                content,
+                signature,
+                sender,
+                sender_name,
+            });
+        }
+
+        Ok(messages)
     }
 
-    // LIMIT
-    pub async fn read_n_messages(&self, limit: i32) -> Result<Vec<Message>, Box<dyn Error>> {
-        let messages = self
+    // IF NOT EXISTS makes two keys racing for a name agree on one winner
+    async fn register_name(&self, name: &RegisteredName) -> Result<bool, StoreError> {
+        let result = self
+            .session
+            .query_unpaged(
+                "INSERT INTO eteedir.user (username, fingerprint, public_key) VALUES (?, ?, ?) IF NOT EXISTS",
+                (&name.name, &name.fingerprint, &name.public_key),
+            )
+            .await?;
+
+        if !applied(result)? {
+            let owner = self.lookup_name(&name.name).await?;
+            if owner.is_some_and(|owner| owner.fingerprint != name.fingerprint) {
+                return Ok(false);
+            }
+        }
+
+        let (previous,) = self
             .session
-            .query_iter("SELECT message FROM eteedir.messages LIMIT ?", (limit,))
+            .query_unpaged(
+                "SELECT username FROM eteedir.user_by_key WHERE fingerprint = ?",
+                (&name.fingerprint,),
+            )
             .await?
-            .into_typed::<Message>();
+            .maybe_first_row_typed::<(Option<String>,)>()?
+            .unwrap_or((None,));
 
-        let vec: Vec<Message> = messages.try_collect().await?;
+        self.session
+            .query_unpaged(
+                "INSERT INTO eteedir.user_by_key (fingerprint, username) VALUES (?, ?)",
+                (&name.fingerprint, &name.name),
+            )
+            .await?;
 
-        Ok(vec)
+        if let Some(previous) = previous.filter(|previous| *previous != name.name) {
+            self.session
+                .query_unpaged(
+                    "DELETE FROM eteedir.user WHERE username = ? IF fingerprint = ?",
+                    (previous, &name.fingerprint),
+                )
+                .await?;
+        }
+
+        Ok(true)
     }
 
-    // ORDER BY
-    pub async fn read_by_order(&self, id: i64) -> Result<Vec<Message>, Box<dyn Error>> {
-        let messages = self
+    async fn lookup_name(&self, name: &str) -> Result<Option<RegisteredName>, StoreError> {
+        let registered = self
             .session
-            .query_iter(
-                "SELECT message FROM eteedir.messages WHERE id = (?) ORDER BY timestamp",
-                (id,),
+            .query_unpaged(
+                "SELECT username, fingerprint, public_key FROM eteedir.user WHERE username = ?",
+                (name,),
             )
             .await?
-            .into_typed::<Message>();
+            .maybe_first_row_typed::<RegisteredName>()?;
+
+        Ok(registered)
+    }
+
+    // Lightweight transactions keep two devices of one key from moving the
+    // position backwards when they race
+    async fn advance_read_position(&self, position: &ReadPosition) -> Result<bool, StoreError> {
+        if self.advance_existing_read_position(position).await? {
+            return Ok(true);
+        }
+
+        let inserted = self
+            .session
+            .query_unpaged(
+                "INSERT INTO eteedir.read_positions (room, fingerprint, name, message_id) VALUES (?, ?, ?, ?) IF NOT EXISTS",
+                (
+                    &position.room,
+                    &position.fingerprint,
+                    &position.name,
+                    position.message_id,
+                ),
+            )
+            .await?;
 
-        let vec: Vec<Message> = messages.try_collect().await?;
+        if applied(inserted)? {
+            return Ok(true);
+        }
 
-        Ok(vec)
+        // Another device stored the first position in the meantime
+        self.advance_existing_read_position(position).await
     }
 
-    // IN
-    pub async fn read_by_in(&self, id1: i64, id2: i64) -> Result<Vec<Message>, Box<dyn Error>> {
-        let messages = self
+    async fn read_positions(&self, room: &str) -> Result<Vec<ReadPosition>, StoreError> {
+        let positions = self
             .session
             .query_iter(
-                "SELECT message FROM eteedir.messages WHERE id in (?, ?)",
-                (id1, id2),
+                "SELECT room, fingerprint, name, message_id FROM eteedir.read_positions WHERE room = ?",
+                (room,),
             )
             .await?
-            .into_typed::<Message>();
-
-        let vec: Vec<Message> = messages.try_collect().await?;
+            .into_typed::<ReadPosition>()
+            .try_collect()
+            .await?;
 
-        Ok(vec)
+        Ok(positions)
     }
 
-    pub async fn update_message(
-        &self,
-        id: i64,
-        timestamp: String,
-        update: String,
-    ) -> Result<(), Box<dyn Error>> {
-        let datetime = chrono::DateTime::parse_from_str(&timestamp, "%Y-%m-%d %H:%M:%S%.f%z")?
-            .with_timezone(&chrono::Utc)
-            .timestamp_millis();
-        let timestamp = scylla::frame::value::CqlTimestamp(datetime);
+    async fn subscribe(&self, room: &str, subscriber: &Subscriber) -> Result<(), StoreError> {
         self.session
             .query_unpaged(
-                "UPDATE eteedir.messages SET message = (?) WHERE id = (?) AND timestamp = (?)",
-                (update.clone(), id, timestamp),
+                "INSERT INTO eteedir.subscriptions (room, fingerprint, name, public_key) VALUES (?, ?, ?, ?)",
+                (
+                    room,
+                    &subscriber.fingerprint,
+                    &subscriber.name,
+                    &subscriber.public_key,
+                ),
             )
             .await?;
 
         Ok(())
     }
 
-    pub async fn delete_message(&mut self, id: i64) -> Result<(), Box<dyn Error>> {
+    async fn unsubscribe(&self, room: &str, fingerprint: &str) -> Result<(), StoreError> {
         self.session
-            .query_unpaged("DELETE FROM eteedir.messages WHERE id = (?)", (id,))
+            .query_unpaged(
+                "DELETE FROM eteedir.subscriptions WHERE room = ? AND fingerprint = ?",
+                (room, fingerprint),
+            )
             .await?;
 
         Ok(())
     }
 
-    // BATCH
-    pub async fn replace_user(
-        &self,
-        username: String,
-        old_user_id: i64,
-    ) -> Result<(), Box<dyn Error>> {
-        let id = rand::thread_rng().gen::<i64>();
-        let mut batch: Batch = Default::default();
+    async fn subscribers(&self, room: &str) -> Result<Vec<Subscriber>, StoreError> {
+        let subscribers = self
+            .session
+            .query_iter(
+                "SELECT fingerprint, name, public_key FROM eteedir.subscriptions WHERE room = ?",
+                (room,),
+            )
+            .await?
+            .into_typed::<Subscriber>()
+            .try_collect()
+            .await?;
+
+        Ok(subscribers)
+    }
+
+    async fn enqueue(&self, item: &Queued, seconds: i32) -> Result<(), StoreError> {
+        self.session
+            .query_unpaged(
+                "INSERT INTO eteedir.delivery_queue (recipient, id, kind, packet) VALUES (?, ?, ?, ?) USING TTL ?",
+                (&item.recipient, item.id, &item.kind, &item.packet, seconds),
+            )
+            .await?;
 
-        batch.append_statement("DELETE FROM eteedir.user WHERE id = (?) ");
-        batch.append_statement("INSERT INTO eteedir.user (id, username) VALUES (?, ?)");
+        Ok(())
+    }
 
-        let batch_values = ((old_user_id,), (id, username));
+    async fn queued(&self, recipient: &str, limit: i32) -> Result<Vec<Queued>, StoreError> {
+        let queued = self
+            .session
+            .query_iter(
+                "SELECT recipient, id, kind, packet FROM eteedir.delivery_queue WHERE recipient = ? LIMIT ?",
+                (recipient, limit),
+            )
+            .await?
+            .into_typed::<Queued>()
+            .try_collect()
+            .await?;
 
+        Ok(queued)
+    }
+
+    async fn dequeue(&self, recipient: &str, up_to: Uuid) -> Result<(), StoreError> {
         self.session
-            .batch(&batch, batch_values)
-            .await
-            .expect("Batch failed to batch things");
+            .query_unpaged(
+                "DELETE FROM eteedir.delivery_queue WHERE recipient = ? AND id <= ?",
+                (recipient, up_to),
+            )
+            .await?;
 
         Ok(())
     }
 }
+
+/// Whether a lightweight transaction went through, from its `[applied]` column
+fn applied(result: QueryResult) -> Result<bool, StoreError> {
+    let row = result.first_row()?;
+    let applied = row
+        .columns
+        .first()
+        .and_then(|column| column.as_ref())
+        .and_then(|value| value.as_boolean());
+
+    Ok(applied.unwrap_or(false))
+}
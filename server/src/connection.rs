@@ -1,29 +1,126 @@
 use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
 use std::net::SocketAddr;
 use std::ops::Deref;
+use std::time::{Duration, Instant};
 
-use common::Packet;
+use common::{
+    feature, Ack, ClientboundChallenge, Encoding, ErrorCode, ErrorPacket, Frame, Packet,
+    ServerboundHandshake, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SIGNATURE_LEN,
+};
 use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
 use openssl::hash::MessageDigest;
-use openssl::pkey::PKey;
+use openssl::pkey::{Id, PKey, Public};
 use openssl::sign::Verifier;
+use rand::Rng;
 use tokio::net::TcpStream;
 use tokio::sync::{mpsc, RwLock};
 use tokio_tungstenite::WebSocketStream;
 
 type Socket = WebSocketStream<TcpStream>;
 
+pub enum ConnectionEvent {
+    Message(tokio_tungstenite::tungstenite::Message),
+    /// The socket was closed or failed. Always the last event for a connection.
+    Disconnected,
+}
+
 pub struct Connection {
-    outbound_msg_send: mpsc::Sender<String>,
+    address: SocketAddr,
+    outbound_msg_send: mpsc::Sender<Frame>,
     public_key: RwLock<Option<PKey<openssl::pkey::Public>>>,
+    /// Display name claimed in the handshake, set together with the public key
+    name: RwLock<Option<String>>,
+    /// Key, name and nonce of a handshake that is waiting for its challenge
+    /// response
+    challenge: RwLock<Option<PendingHandshake>>,
+    /// Negotiated protocol version, set together with the public key
+    protocol_version: RwLock<u32>,
+    /// Features both sides support, set together with the public key
+    features: RwLock<Vec<String>>,
+    /// How packets to the client are encoded. Stays JSON until a handshake
+    /// with [`feature::CBOR`] completes.
+    encoding: RwLock<Encoding>,
+    /// When a typing indicator from this client was last relayed, per room
+    last_typing: RwLock<HashMap<String, Instant>>,
+    /// Whether a queue batch was sent that the client hasn't acked yet
+    queue_batch_pending: RwLock<bool>,
+}
+
+struct PendingHandshake {
+    public_key: PKey<Public>,
+    name: String,
+    nonce: String,
+    protocol_version: u32,
+    features: Vec<String>,
+}
+
+const MAX_NAME_LEN: usize = 32;
+/// Least time between two typing indicators relayed from one client
+const TYPING_THROTTLE: Duration = Duration::from_secs(1);
+
+/// Display names and room names can't be empty or contain whitespace
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Fingerprint of a PEM public key, or `None` if it isn't one
+pub fn pem_fingerprint(pem: &[u8]) -> Option<String> {
+    let der = PKey::public_key_from_pem(pem)
+        .ok()?
+        .public_key_to_der()
+        .ok()?;
+    Some(common::key_fingerprint(&der))
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    AlreadyStarted,
+    /// The client's newest version is older than the server can decode
+    UnsupportedVersion(u32),
+    InvalidKey(openssl::error::ErrorStack),
+    /// A key that isn't RSA-2048, whose signatures wouldn't be
+    /// [`SIGNATURE_LEN`] bytes
+    UnsupportedKey,
+    InvalidName,
+}
+
+impl HandshakeError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            HandshakeError::AlreadyStarted => ErrorCode::HandshakeInProgress,
+            HandshakeError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            HandshakeError::InvalidKey(_) | HandshakeError::UnsupportedKey => ErrorCode::InvalidKey,
+            HandshakeError::InvalidName => ErrorCode::InvalidName,
+        }
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::AlreadyStarted => write!(f, "handshake was already sent"),
+            HandshakeError::UnsupportedVersion(version) => write!(
+                f,
+                "protocol version {} is unsupported, server speaks {} to {}",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::InvalidKey(e) => write!(f, "invalid public key: {}", e),
+            HandshakeError::UnsupportedKey => write!(f, "public key must be RSA-2048"),
+            HandshakeError::InvalidName => write!(f, "invalid display name"),
+        }
+    }
 }
 
 impl Connection {
     pub fn new(
         socket: Socket,
         address: SocketAddr,
-        inbound_messages: mpsc::Sender<(SocketAddr, tokio_tungstenite::tungstenite::Message)>,
+        inbound_messages: mpsc::Sender<(SocketAddr, ConnectionEvent)>,
     ) -> Connection {
         let (write, read) = socket.split();
         let (outbound_send, outbound_recv) = mpsc::channel(16);
@@ -32,25 +129,64 @@ impl Connection {
         tokio::spawn(Self::write_loop(write, outbound_recv));
 
         Connection {
+            address,
             outbound_msg_send: outbound_send,
             public_key: RwLock::new(None),
+            name: RwLock::new(None),
+            challenge: RwLock::new(None),
+            protocol_version: RwLock::new(MIN_PROTOCOL_VERSION),
+            features: RwLock::new(Vec::new()),
+            encoding: RwLock::new(Encoding::Json),
+            last_typing: RwLock::new(HashMap::new()),
+            queue_batch_pending: RwLock::new(false),
         }
     }
 
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
     pub async fn queue_packet<P: Packet>(&self, packet: P) {
-        let _ = self.outbound_msg_send.send(packet.network_encode()).await;
+        let frame = packet.encode(*self.encoding.read().await);
+        let _ = self.outbound_msg_send.send(frame).await;
+    }
+
+    /// Logs why a packet was rejected and tells the client
+    pub async fn send_error(
+        &self,
+        code: ErrorCode,
+        message: impl Into<String>,
+        correlation_id: Option<String>,
+    ) {
+        let message = message.into();
+        eprintln!("rejected packet from {}: {}", self.address, message);
+
+        self.queue_packet(ErrorPacket {
+            code,
+            message,
+            correlation_id,
+        })
+        .await;
+    }
+
+    /// Acknowledges a packet if the client gave it a correlation ID
+    pub async fn send_ack(&self, correlation_id: Option<String>, id: Option<String>) {
+        if let Some(correlation_id) = correlation_id {
+            self.queue_packet(Ack { correlation_id, id }).await;
+        }
     }
 
     pub async fn read_loop(
         mut read: SplitStream<Socket>,
         address: SocketAddr,
-        inbound_messages: mpsc::Sender<(SocketAddr, tokio_tungstenite::tungstenite::Message)>,
+        inbound_messages: mpsc::Sender<(SocketAddr, ConnectionEvent)>,
     ) -> Result<(), tokio_tungstenite::tungstenite::Error> {
         while let Some(msg) = read.next().await {
             match msg {
                 Ok(m) => {
-                    if let Err(_) = inbound_messages.send((address, m)).await {
-                        break;
+                    let event = ConnectionEvent::Message(m);
+                    if inbound_messages.send((address, event)).await.is_err() {
+                        return Ok(());
                     }
                 }
                 Err(e) => {
@@ -60,15 +196,21 @@ impl Connection {
             }
         }
 
+        let _ = inbound_messages
+            .send((address, ConnectionEvent::Disconnected))
+            .await;
         Ok(())
     }
 
     pub async fn write_loop(
         mut write: SplitSink<Socket, tokio_tungstenite::tungstenite::Message>,
-        mut outbound_messages: mpsc::Receiver<String>,
+        mut outbound_messages: mpsc::Receiver<Frame>,
     ) -> Result<(), tokio_tungstenite::tungstenite::Error> {
-        while let Some(encoded_packet) = outbound_messages.recv().await {
-            let ws_message = tokio_tungstenite::tungstenite::Message::Text(encoded_packet);
+        while let Some(frame) = outbound_messages.recv().await {
+            let ws_message = match frame {
+                Frame::Text(text) => tokio_tungstenite::tungstenite::Message::Text(text),
+                Frame::Binary(bytes) => tokio_tungstenite::tungstenite::Message::Binary(bytes),
+            };
             write.send(ws_message).await?;
         }
 
@@ -79,21 +221,149 @@ impl Connection {
         return self.public_key.read().await.is_some();
     }
 
-    pub async fn set_public_key(&self, pem: &[u8]) -> Result<(), openssl::error::ErrorStack> {
-        let pkey = PKey::public_key_from_pem(pem)?;
+    /// Stores the handshake's key as pending and returns the challenge the
+    /// client has to sign before the key is accepted
+    pub async fn begin_handshake(
+        &self,
+        handshake: &ServerboundHandshake,
+    ) -> Result<ClientboundChallenge, HandshakeError> {
+        let mut challenge = self.challenge.write().await;
+        if challenge.is_some() || self.has_public_key().await {
+            return Err(HandshakeError::AlreadyStarted);
+        }
+
+        let protocol_version = common::negotiate_version(handshake.protocol_version).ok_or(
+            HandshakeError::UnsupportedVersion(handshake.protocol_version),
+        )?;
+
+        if !is_valid_name(&handshake.name) {
+            return Err(HandshakeError::InvalidName);
+        }
+
+        let public_key = PKey::public_key_from_pem(handshake.public_key.as_bytes())
+            .map_err(HandshakeError::InvalidKey)?;
+        if public_key.id() != Id::RSA || public_key.size() != SIGNATURE_LEN {
+            return Err(HandshakeError::UnsupportedKey);
+        }
+
+        let nonce: String = rand::thread_rng()
+            .gen::<[u8; 32]>()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let features = common::common_features(&handshake.features);
+
+        let _ = challenge.insert(PendingHandshake {
+            public_key,
+            name: handshake.name.clone(),
+            nonce: nonce.clone(),
+            protocol_version,
+            features: features.clone(),
+        });
+        Ok(ClientboundChallenge {
+            nonce,
+            protocol_version,
+            features,
+        })
+    }
+
+    /// Commits the pending key if `signature` is a valid signature of the
+    /// challenge nonce. A failed attempt discards the pending handshake.
+    pub async fn complete_handshake(&self, signature: &[u8]) -> bool {
+        let Some(pending) = self.challenge.write().await.take() else {
+            return false;
+        };
+
+        if !Self::verify(&pending.public_key, pending.nonce.as_bytes(), signature) {
+            return false;
+        }
+
+        let _ = self.name.write().await.insert(pending.name);
+        *self.protocol_version.write().await = pending.protocol_version;
+        if pending.features.iter().any(|f| f == feature::CBOR) {
+            *self.encoding.write().await = Encoding::Cbor;
+        }
+        *self.features.write().await = pending.features;
+        self.set_public_key(pending.public_key).await;
+        true
+    }
+
+    async fn set_public_key(&self, pkey: PKey<Public>) {
         let _ = self.public_key.write().await.insert(pkey);
-        Ok(())
     }
 
-    pub async fn verify_signature(&self, data: &[u8], signature: &[u8]) -> bool {
-        let public_key_read = self.public_key.read().await;
-        let public_key = public_key_read.as_ref().unwrap();
+    /// Whether enough time has passed since the last typing indicator in
+    /// `room` to relay another one. Records the indicator if so.
+    pub async fn allow_typing(&self, room: &str) -> bool {
+        let mut last_typing = self.last_typing.write().await;
+        if last_typing
+            .get(room)
+            .is_some_and(|t| t.elapsed() < TYPING_THROTTLE)
+        {
+            return false;
+        }
+
+        last_typing.insert(room.to_owned(), Instant::now());
+        true
+    }
+
+    /// Lets the next typing indicator in `room` through right away
+    pub async fn stop_typing(&self, room: &str) {
+        self.last_typing.write().await.remove(room);
+    }
 
-        let mut verifier =
-            Verifier::new(MessageDigest::sha256(), &public_key).expect("failed to create verifier");
+    /// Marks a queue batch as sent, or returns `false` if the last one is
+    /// still waiting for its ack
+    pub async fn start_queue_batch(&self) -> bool {
+        let mut pending = self.queue_batch_pending.write().await;
+        !std::mem::replace(&mut *pending, true)
+    }
+
+    pub async fn finish_queue_batch(&self) {
+        *self.queue_batch_pending.write().await = false;
+    }
+
+    pub async fn name(&self) -> Option<String> {
+        self.name.read().await.clone()
+    }
+
+    pub async fn set_name(&self, name: String) {
+        let _ = self.name.write().await.insert(name);
+    }
+
+    /// Whether the client can handle packets belonging to `feature`
+    pub async fn supports(&self, feature: &str) -> bool {
+        common::supports(
+            *self.protocol_version.read().await,
+            &self.features.read().await,
+            feature,
+        )
+    }
+
+    pub async fn public_key_pem(&self) -> Option<String> {
+        let public_key = self.public_key.read().await;
+        let pem = public_key.as_ref()?.public_key_to_pem().ok()?;
+        String::from_utf8(pem).ok()
+    }
+
+    pub async fn fingerprint(&self) -> Option<String> {
+        let public_key = self.public_key.read().await;
+        let der = public_key.as_ref()?.public_key_to_der().ok()?;
+        Some(common::key_fingerprint(&der))
+    }
+
+    pub async fn verify_signature(&self, data: &[u8], signature: &[u8]) -> bool {
+        match self.public_key.read().await.as_ref() {
+            Some(public_key) => Self::verify(public_key, data, signature),
+            None => false,
+        }
+    }
 
-        verifier.update(data).expect("failed to update verifier");
+    fn verify(public_key: &PKey<Public>, data: &[u8], signature: &[u8]) -> bool {
+        let Ok(mut verifier) = Verifier::new(MessageDigest::sha256(), public_key) else {
+            return false;
+        };
 
-        verifier.verify(signature).is_ok()
+        verifier.update(data).is_ok() && verifier.verify(signature).unwrap_or(false)
     }
 }
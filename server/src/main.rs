@@ -1,70 +1,114 @@
 mod cassandra;
 mod connection;
+mod memory;
+mod migrations;
+mod sqlite;
+mod store;
+#[cfg(test)]
+mod store_tests;
+#[cfg(test)]
+mod tests;
 
 use cassandra::Cassandra;
-use common::{MessagePacket, Packet, ServerboundHandshake};
-use connection::Connection;
+use common::{
+    feature, DecodeError, DeleteMessage, DirectMessagePacket, EditMessage, ErrorCode, Frame,
+    HistoryPage, HistoryRequest, JoinRoom, LeaveRoom, ListMembers, LookupName, Member, MemberList,
+    MessagePacket, NameInfo, Packet, PeerKeyPacket, Presence, QueueAck, QueueBatchEnd,
+    ReadPositions, ReadUpTo, RegisterName, ServerboundChallengeResponse, ServerboundHandshake,
+    TypingStarted, TypingStopped,
+};
+use connection::{Connection, ConnectionEvent};
+use memory::MemoryStore;
 use openssl::hash::MessageDigest;
 use openssl::sign::{Signer, Verifier};
-use std::collections::HashMap;
+use sqlite::Sqlite;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use store::{InvalidCursor, MessageStore, Queued, ReadPosition, RegisteredName, Subscriber};
 use tokio::net::TcpListener;
 use tokio::sync::{mpsc, Mutex, RwLock};
 use tokio_tungstenite::accept_async;
+use uuid::Uuid;
+
+/// Most messages sent in a single history page
+const MAX_HISTORY_PAGE: u32 = 100;
+/// Longest a disappearing message can be kept, in seconds
+const MAX_MESSAGE_TTL: u32 = 7 * 24 * 60 * 60;
+/// Most queued packets sent before waiting for a [`QueueAck`]
+const QUEUE_BATCH: i32 = 100;
+/// Longest a packet waits for a key that doesn't come back, in seconds
+const QUEUE_TTL: u32 = 30 * 24 * 60 * 60;
 
 struct Server {
     map: RwLock<HashMap<SocketAddr, Arc<Connection>>>,
+    /// Members of each room by address
+    rooms: RwLock<HashMap<String, HashSet<SocketAddr>>>,
     connection: TcpListener,
-    dal: Arc<cassandra::Cassandra>,
-    inbound_msg_send: mpsc::Sender<(SocketAddr, tokio_tungstenite::tungstenite::Message)>,
-    inbound_msg_recv: Mutex<mpsc::Receiver<(SocketAddr, tokio_tungstenite::tungstenite::Message)>>,
+    dal: Arc<dyn MessageStore>,
+    inbound_msg_send: mpsc::Sender<(SocketAddr, ConnectionEvent)>,
+    inbound_msg_recv: Mutex<mpsc::Receiver<(SocketAddr, ConnectionEvent)>>,
 }
 
 impl Server {
     pub async fn accept_loop(self: Arc<Server>) {
         loop {
-            let (stream, address) = self.connection.accept().await.unwrap();
+            let (stream, address) = match self.connection.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
 
-            let socket = accept_async(stream).await.unwrap();
+            let socket = match accept_async(stream).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    eprintln!("websocket handshake with {} failed: {}", address, e);
+                    continue;
+                }
+            };
             let connection = Arc::new(Connection::new(
                 socket,
                 address,
                 self.inbound_msg_send.clone(),
             ));
 
-            self.map.write().await.insert(address, connection.clone());
-
-            let cloned_self = self.clone();
-            tokio::spawn(async move {
-                let history = cloned_self.dal.read_messages().await.unwrap();
-
-                for item in history {
-                    connection
-                        .queue_packet(MessagePacket {
-                            content: item.content,
-                            signature: item.signature,
-                        })
-                        .await;
-                }
-            });
+            self.map.write().await.insert(address, connection);
         }
     }
 
     pub async fn run(self: &Arc<Server>) {
         let mut inbound_msg_recv = self.inbound_msg_recv.lock().await;
-        while let Some((address, message)) = inbound_msg_recv.recv().await {
-            self.packet_received(address, message).await;
+        while let Some((address, event)) = inbound_msg_recv.recv().await {
+            match event {
+                ConnectionEvent::Message(message) => self.packet_received(address, message).await,
+                ConnectionEvent::Disconnected => self.disconnected(address).await,
+            }
         }
     }
 
+    /// Forgets a closed connection. Dropping the last reference to it also
+    /// stops its write task.
+    async fn disconnected(&self, address: SocketAddr) {
+        let Some(connection) = self.map.write().await.remove(&address) else {
+            return;
+        };
+
+        self.rooms.write().await.retain(|_, members| {
+            members.remove(&address);
+            !members.is_empty()
+        });
+
+        self.broadcast_presence(&connection).await;
+    }
+
     pub async fn packet_received(
         self: &Arc<Server>,
         client_address: SocketAddr,
         message: tokio_tungstenite::tungstenite::Message,
     ) {
-        let read_map = self.map.read().await;
-        let sender = match read_map.get(&client_address) {
+        let sender = match self.map.read().await.get(&client_address).cloned() {
             Some(client) => client,
             None => {
                 eprintln!(
@@ -75,36 +119,275 @@ impl Server {
             }
         };
 
-        let raw_text = match message {
-            tokio_tungstenite::tungstenite::Message::Text(text) => text,
-            other => {
-                eprintln!("unacceptable client message: {}", other);
+        // Either encoding is accepted at any time, whatever was negotiated
+        let frame = match message {
+            tokio_tungstenite::tungstenite::Message::Text(text) => Frame::Text(text),
+            tokio_tungstenite::tungstenite::Message::Binary(bytes) => Frame::Binary(bytes),
+            // Control frames are answered by tungstenite
+            _ => return,
+        };
+
+        let (id, body) = match frame.split() {
+            Ok(parts) => parts,
+            Err(e) => {
+                sender
+                    .send_error(ErrorCode::MalformedPacket, e.to_string(), None)
+                    .await;
                 return;
             }
         };
 
-        let (id, json_data) = common::network_decode(&raw_text).unwrap();
-
         macro_rules! parse_packets {
             ($($packet_type:ident => $func:ident),* $(,)?) => {
                 match id {
                 $(
-                    $packet_type::ID => self.$func(&sender, serde_json::from_str(json_data).unwrap()).await,
+                    $packet_type::ID => match $packet_type::decode(body) {
+                        Ok(packet) => self.$func(&sender, packet).await,
+                        Err(e) => {
+                            sender
+                                .send_error(ErrorCode::MalformedPacket, e.to_string(), None)
+                                .await
+                        }
+                    },
                 )*
-                    other => eprintln!("unexpected packet ID {}", other),
+                    other => {
+                        let error = DecodeError::UnknownPacket(other.to_owned());
+                        sender
+                            .send_error(ErrorCode::UnknownPacket, error.to_string(), None)
+                            .await
+                    }
                 }
             }
         }
 
         parse_packets!(
             MessagePacket => handle_message,
+            DirectMessagePacket => handle_direct_message,
             ServerboundHandshake => handle_serverbound_handshake,
+            ServerboundChallengeResponse => handle_challenge_response,
+            JoinRoom => handle_join_room,
+            LeaveRoom => handle_leave_room,
+            HistoryRequest => handle_history_request,
+            EditMessage => handle_edit_message,
+            DeleteMessage => handle_delete_message,
+            RegisterName => handle_register_name,
+            LookupName => handle_lookup_name,
+            ListMembers => handle_list_members,
+            TypingStarted => handle_typing_started,
+            TypingStopped => handle_typing_stopped,
+            ReadUpTo => handle_read_up_to,
+            QueueAck => handle_queue_ack,
+        );
+    }
+
+    async fn is_member(&self, room: &str, address: SocketAddr) -> bool {
+        self.rooms
+            .read()
+            .await
+            .get(room)
+            .is_some_and(|members| members.contains(&address))
+    }
+
+    /// Sends a packet to every member of a room
+    async fn broadcast<P: Packet + Clone>(&self, room: &str, packet: P) {
+        let rooms = self.rooms.read().await;
+        let map = self.map.read().await;
+
+        let members = rooms.get(room).into_iter().flatten();
+        for client in members.filter_map(|address| map.get(address)) {
+            client.queue_packet(packet.clone()).await;
+        }
+    }
+
+    /// Sends a packet to the members of a room that support `feature`
+    async fn broadcast_feature<P: Packet + Clone>(&self, room: &str, feature: &str, packet: P) {
+        let rooms = self.rooms.read().await;
+        let map = self.map.read().await;
+
+        let members = rooms.get(room).into_iter().flatten();
+        for client in members.filter_map(|address| map.get(address)) {
+            if client.supports(feature).await {
+                client.queue_packet(packet.clone()).await;
+            }
+        }
+    }
+
+    /// Queues a packet sent to a room for every subscriber with no connection
+    /// in it, so they get it when they next connect. A packet with a `ttl` is
+    /// dropped from the queue when it would have disappeared.
+    async fn enqueue_for_absent<P: Packet>(&self, room: &str, packet: &P, ttl: Option<u32>) {
+        let subscribers = match self.dal.subscribers(room).await {
+            Ok(subscribers) => subscribers,
+            Err(e) => {
+                eprintln!("failed to read subscribers of {}: {}", room, e);
+                return;
+            }
+        };
+
+        let mut present = HashSet::new();
+        {
+            let rooms = self.rooms.read().await;
+            let map = self.map.read().await;
+            let members = rooms.get(room).into_iter().flatten();
+            for client in members.filter_map(|address| map.get(address)) {
+                present.extend(client.fingerprint().await);
+            }
+        }
+
+        let json = serde_json::to_string(packet).expect("couldn't encode packet to json");
+        for subscriber in subscribers {
+            if !present.contains(&subscriber.fingerprint) {
+                self.enqueue(subscriber.fingerprint, P::ID, json.clone(), ttl)
+                    .await;
+            }
+        }
+    }
+
+    async fn enqueue(
+        &self,
+        recipient: String,
+        kind: &str,
+        packet: String,
+        ttl: Option<u32>,
+    ) -> bool {
+        let item = Queued {
+            recipient,
+            id: Uuid::now_v7(),
+            kind: kind.to_owned(),
+            packet,
+        };
+
+        let ttl = ttl.map_or(QUEUE_TTL, |ttl| ttl.min(QUEUE_TTL));
+        match self.dal.enqueue(&item, ttl as i32).await {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!(
+                    "failed to queue {} for {}: {}",
+                    item.kind, item.recipient, e
+                );
+                false
+            }
+        }
+    }
+
+    /// Sends the oldest packets queued for `conn`'s key, unless a batch is
+    /// already waiting for its ack. Clients without [`feature::QUEUE`] can't
+    /// ack, so their queue is left for a device that can.
+    async fn deliver_queue(&self, conn: &Arc<Connection>) {
+        if !conn.supports(feature::QUEUE).await {
+            return;
+        }
+
+        let Some(fingerprint) = conn.fingerprint().await else {
+            return;
+        };
+
+        if !conn.start_queue_batch().await {
+            return;
+        }
+
+        let batch = match self.dal.queued(&fingerprint, QUEUE_BATCH).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                eprintln!("failed to read the queue of {}: {}", fingerprint, e);
+                conn.finish_queue_batch().await;
+                return;
+            }
+        };
+
+        let Some(up_to) = batch.last().map(|item| item.id) else {
+            conn.finish_queue_batch().await;
+            return;
+        };
+        let more = batch.len() == QUEUE_BATCH as usize;
+
+        for item in batch {
+            self.forward_queued(conn, item).await;
+        }
+
+        conn.queue_packet(QueueBatchEnd {
+            up_to: up_to.to_string(),
+            more,
+        })
+        .await;
+    }
+
+    async fn forward_queued(&self, conn: &Arc<Connection>, item: Queued) {
+        let edits = item.kind == EditMessage::ID || item.kind == DeleteMessage::ID;
+        if edits && !conn.supports(feature::EDIT).await {
+            return;
+        }
+
+        macro_rules! forward_packets {
+            ($($packet_type:ident),* $(,)?) => {
+                match item.kind.as_str() {
+                $(
+                    $packet_type::ID => match common::decode_packet::<$packet_type>(&item.packet) {
+                        Ok(packet) => conn.queue_packet(packet).await,
+                        Err(e) => eprintln!("queued {} {} is invalid: {}", item.kind, item.id, e),
+                    },
+                )*
+                    other => eprintln!("queued {} has unknown packet {}", item.id, other),
+                }
+            }
+        }
+
+        forward_packets!(
+            MessagePacket,
+            EditMessage,
+            DeleteMessage,
+            DirectMessagePacket
         );
     }
 
-    async fn handle_message(&self, conn: &Arc<Connection>, message: MessagePacket) {
+    /// How many handshaken connections are using the key with `fingerprint`
+    async fn devices_online(&self, fingerprint: &str) -> u32 {
+        let mut devices = 0;
+        for client in self.map.read().await.values() {
+            if client.fingerprint().await.as_deref() == Some(fingerprint) {
+                devices += 1;
+            }
+        }
+
+        devices
+    }
+
+    /// Tells everyone else how many devices `conn`'s key has connected after
+    /// it connected or disconnected
+    async fn broadcast_presence(&self, conn: &Connection) {
+        let (Some(public_key), Some(name), Some(fingerprint)) = (
+            conn.public_key_pem().await,
+            conn.name().await,
+            conn.fingerprint().await,
+        ) else {
+            return;
+        };
+
+        let presence = Presence {
+            public_key,
+            name,
+            devices: self.devices_online(&fingerprint).await,
+        };
+
+        for client in self.map.read().await.values() {
+            if client.address() == conn.address()
+                || !client.has_public_key().await
+                || !client.supports(feature::PRESENCE).await
+            {
+                continue;
+            }
+
+            client.queue_packet(presence.clone()).await;
+        }
+    }
+
+    async fn handle_message(&self, conn: &Arc<Connection>, mut message: MessagePacket) {
+        let correlation_id = message.correlation_id.take();
+
         if !conn.has_public_key().await {
-            eprintln!("tried to send a message without sending its public key");
+            let error = "tried to send a message without sending its public key";
+            conn.send_error(ErrorCode::NoHandshake, error, correlation_id)
+                .await;
             return;
         }
 
@@ -112,20 +395,659 @@ impl Server {
             .verify_signature(message.content.as_bytes(), &message.signature)
             .await
         {
-            eprintln!("message signature mismatch");
+            let error = "message signature mismatch";
+            conn.send_error(ErrorCode::BadSignature, error, correlation_id)
+                .await;
+            return;
+        }
+
+        if !self.is_member(&message.room, conn.address()).await {
+            let error = format!(
+                "tried to send a message to room {} without joining",
+                message.room
+            );
+            conn.send_error(ErrorCode::NotInRoom, error, correlation_id)
+                .await;
             return;
         }
 
-        let db_msg = cassandra::Message {
+        let id = Uuid::now_v7();
+        let timestamp = chrono::Utc::now().timestamp_millis();
+
+        message.sender = conn.public_key_pem().await;
+        message.sender_name = conn.name().await;
+        message.id = Some(id.to_string());
+        message.timestamp = Some(timestamp);
+
+        message.ttl = message.ttl.map(|ttl| ttl.clamp(1, MAX_MESSAGE_TTL));
+        message.expires_at = message.ttl.map(|ttl| timestamp + i64::from(ttl) * 1000);
+
+        let db_msg = store::Message {
+            room: message.room.clone(),
+            id,
+            timestamp,
             content: message.content.clone(),
             signature: message.signature.clone(),
+            sender: message.sender.clone(),
+            sender_name: message.sender_name.clone(),
+            edited_at: None,
+            expires_at: message.expires_at,
+        };
+
+        let stored = match message.ttl {
+            Some(ttl) => self.dal.insert_message_ttl(&db_msg, ttl as i32).await,
+            None => self.dal.insert_message(&db_msg).await,
+        };
+
+        if let Err(e) = stored {
+            eprintln!("failed to store message: {}", e);
+            let error = "couldn't store the message";
+            conn.send_error(ErrorCode::Internal, error, correlation_id)
+                .await;
+            return;
+        }
+
+        // Ack first so the sender can match the broadcast to its pending copy
+        conn.send_ack(correlation_id, message.id.clone()).await;
+        self.broadcast(&db_msg.room, message.clone()).await;
+        self.enqueue_for_absent(&db_msg.room, &message, message.ttl)
+            .await;
+    }
+
+    async fn handle_edit_message(&self, conn: &Arc<Connection>, mut edit: EditMessage) {
+        let correlation_id = edit.correlation_id.take();
+
+        if !conn.has_public_key().await {
+            let error = "tried to edit a message without sending its public key";
+            conn.send_error(ErrorCode::NoHandshake, error, correlation_id)
+                .await;
+            return;
+        }
+
+        let signed_data = EditMessage::signed_data(&edit.room, &edit.id, &edit.content);
+        if !conn.verify_signature(&signed_data, &edit.signature).await {
+            let error = "edit signature mismatch";
+            conn.send_error(ErrorCode::BadSignature, error, correlation_id)
+                .await;
+            return;
+        }
+
+        let id = match self.authorize_author(conn, &edit.room, &edit.id).await {
+            Ok(id) => id,
+            Err((code, error)) => {
+                conn.send_error(code, error, correlation_id).await;
+                return;
+            }
         };
 
-        self.dal.insert_message(&db_msg).await.unwrap();
+        let edited_at = chrono::Utc::now().timestamp_millis();
+        let updated = self
+            .dal
+            .update_message(
+                &edit.room,
+                id,
+                edit.content.clone(),
+                edit.signature.clone(),
+                edited_at,
+            )
+            .await;
+
+        if let Err(e) = updated {
+            eprintln!("failed to edit message: {}", e);
+            let error = "couldn't edit the message";
+            conn.send_error(ErrorCode::Internal, error, correlation_id)
+                .await;
+            return;
+        }
 
+        conn.send_ack(correlation_id, None).await;
+        let room = edit.room.clone();
+        self.broadcast_feature(&room, feature::EDIT, edit.clone())
+            .await;
+        self.enqueue_for_absent(&room, &edit, None).await;
+    }
+
+    async fn handle_delete_message(&self, conn: &Arc<Connection>, mut delete: DeleteMessage) {
+        let correlation_id = delete.correlation_id.take();
+
+        if !conn.has_public_key().await {
+            let error = "tried to delete a message without sending its public key";
+            conn.send_error(ErrorCode::NoHandshake, error, correlation_id)
+                .await;
+            return;
+        }
+
+        let signed_data = DeleteMessage::signed_data(&delete.room, &delete.id);
+        if !conn.verify_signature(&signed_data, &delete.signature).await {
+            let error = "delete signature mismatch";
+            conn.send_error(ErrorCode::BadSignature, error, correlation_id)
+                .await;
+            return;
+        }
+
+        let id = match self.authorize_author(conn, &delete.room, &delete.id).await {
+            Ok(id) => id,
+            Err((code, error)) => {
+                conn.send_error(code, error, correlation_id).await;
+                return;
+            }
+        };
+
+        if let Err(e) = self.dal.delete_message(&delete.room, id).await {
+            eprintln!("failed to delete message: {}", e);
+            let error = "couldn't delete the message";
+            conn.send_error(ErrorCode::Internal, error, correlation_id)
+                .await;
+            return;
+        }
+
+        conn.send_ack(correlation_id, None).await;
+        let room = delete.room.clone();
+        self.broadcast_feature(&room, feature::EDIT, delete.clone())
+            .await;
+        self.enqueue_for_absent(&room, &delete, None).await;
+    }
+
+    /// Parses a message ID, checking that the message exists in a room `conn`
+    /// has joined and that `conn` sent it
+    async fn authorize_author(
+        &self,
+        conn: &Arc<Connection>,
+        room: &str,
+        id: &str,
+    ) -> Result<Uuid, (ErrorCode, String)> {
+        let (id, message) = self.find_message(conn, room, id).await?;
+
+        if message.sender.is_none() || message.sender != conn.public_key_pem().await {
+            return Err((
+                ErrorCode::NotAuthor,
+                format!("tried to change message {} sent by someone else", id),
+            ));
+        }
+
+        Ok(id)
+    }
+
+    /// Parses a message ID and looks the message up in a room `conn` has
+    /// joined
+    async fn find_message(
+        &self,
+        conn: &Arc<Connection>,
+        room: &str,
+        id: &str,
+    ) -> Result<(Uuid, store::Message), (ErrorCode, String)> {
+        if !self.is_member(room, conn.address()).await {
+            return Err((
+                ErrorCode::NotInRoom,
+                format!("referred to a message in room {} without joining", room),
+            ));
+        }
+
+        let Ok(id) = Uuid::parse_str(id) else {
+            return Err((
+                ErrorCode::InvalidMessageId,
+                format!("invalid message ID {}", id),
+            ));
+        };
+
+        match self.dal.get_message(room, id).await {
+            Ok(Some(message)) => Ok((id, message)),
+            Ok(None) => Err((ErrorCode::UnknownMessage, format!("unknown message {}", id))),
+            Err(e) => {
+                eprintln!("failed to read message {}: {}", id, e);
+                Err((ErrorCode::Internal, "couldn't read the message".to_owned()))
+            }
+        }
+    }
+
+    async fn handle_direct_message(
+        &self,
+        conn: &Arc<Connection>,
+        mut message: DirectMessagePacket,
+    ) {
+        let correlation_id = message.correlation_id.take();
+
+        if !conn.has_public_key().await {
+            let error = "tried to send a direct message without sending its public key";
+            conn.send_error(ErrorCode::NoHandshake, error, correlation_id)
+                .await;
+            return;
+        }
+
+        if !conn
+            .verify_signature(message.content.as_bytes(), &message.signature)
+            .await
+        {
+            let error = "direct message signature mismatch";
+            conn.send_error(ErrorCode::BadSignature, error, correlation_id)
+                .await;
+            return;
+        }
+
+        message.sender = conn.public_key_pem().await;
+        message.sender_name = conn.name().await;
+
+        // Clients that send a correlation ID show their own message right away
+        // and get an Ack instead of an echo
+        let echo = correlation_id.is_none();
+
+        let mut delivered = false;
         for client in self.map.read().await.values() {
-            client.queue_packet(message.clone()).await;
+            if client.fingerprint().await.as_ref() == Some(&message.recipient) {
+                if echo || !Arc::ptr_eq(client, conn) {
+                    client.queue_packet(message.clone()).await;
+                }
+                delivered = true;
+            }
         }
+
+        if !delivered {
+            let json = serde_json::to_string(&message).expect("couldn't encode packet to json");
+            if !self
+                .enqueue(
+                    message.recipient.clone(),
+                    DirectMessagePacket::ID,
+                    json,
+                    None,
+                )
+                .await
+            {
+                let error = "couldn't store the direct message";
+                conn.send_error(ErrorCode::Internal, error, correlation_id)
+                    .await;
+                return;
+            }
+        }
+
+        if !echo {
+            conn.send_ack(correlation_id, None).await;
+        } else if conn.fingerprint().await.as_ref() != Some(&message.recipient) {
+            // They messaged themselves and already got it above otherwise
+            conn.queue_packet(message).await;
+        }
+    }
+
+    async fn handle_join_room(&self, conn: &Arc<Connection>, packet: JoinRoom) {
+        if !conn.has_public_key().await {
+            let error = "tried to join a room without sending its public key";
+            conn.send_error(ErrorCode::NoHandshake, error, None).await;
+            return;
+        }
+
+        if !connection::is_valid_name(&packet.room) {
+            let error = format!("invalid room name {}", packet.room);
+            conn.send_error(ErrorCode::InvalidRoom, error, None).await;
+            return;
+        }
+
+        self.rooms
+            .write()
+            .await
+            .entry(packet.room.clone())
+            .or_default()
+            .insert(conn.address());
+
+        self.subscribe(conn, &packet.room).await;
+
+        if conn.supports(feature::RECEIPTS).await {
+            self.send_read_positions(conn, packet.room).await;
+        }
+
+        // Packets for this room queued while the client wasn't in it yet
+        self.deliver_queue(conn).await;
+    }
+
+    /// Remembers `conn`'s key as a subscriber of `room` and sends it the keys
+    /// of the subscribers that aren't connected, so what it sends the room can
+    /// be read by them too. Connected ones were sent in the handshake.
+    async fn subscribe(&self, conn: &Arc<Connection>, room: &str) {
+        let (Some(fingerprint), Some(name), Some(public_key)) = (
+            conn.fingerprint().await,
+            conn.name().await,
+            conn.public_key_pem().await,
+        ) else {
+            return;
+        };
+
+        let subscriber = Subscriber {
+            fingerprint,
+            name,
+            public_key,
+        };
+        if let Err(e) = self.dal.subscribe(room, &subscriber).await {
+            eprintln!(
+                "failed to subscribe {} to {}: {}",
+                subscriber.fingerprint, room, e
+            );
+        }
+
+        let subscribers = match self.dal.subscribers(room).await {
+            Ok(subscribers) => subscribers,
+            Err(e) => {
+                eprintln!("failed to read subscribers of {}: {}", room, e);
+                return;
+            }
+        };
+
+        let mut online = HashSet::new();
+        for client in self.map.read().await.values() {
+            online.extend(client.fingerprint().await);
+        }
+
+        for subscriber in subscribers {
+            if !online.contains(&subscriber.fingerprint) {
+                conn.queue_packet(PeerKeyPacket {
+                    public_key: subscriber.public_key,
+                    name: subscriber.name,
+                })
+                .await;
+            }
+        }
+    }
+
+    async fn send_read_positions(&self, conn: &Arc<Connection>, room: String) {
+        let positions = match self.dal.read_positions(&room).await {
+            Ok(positions) => positions,
+            Err(e) => {
+                eprintln!("failed to read read positions of {}: {}", room, e);
+                return;
+            }
+        };
+
+        let positions = positions
+            .into_iter()
+            .map(|position| ReadUpTo {
+                room: position.room,
+                message_id: position.message_id.to_string(),
+                fingerprint: Some(position.fingerprint),
+                sender_name: Some(position.name),
+            })
+            .collect();
+        conn.queue_packet(ReadPositions { room, positions }).await;
+    }
+
+    async fn handle_read_up_to(&self, conn: &Arc<Connection>, mut packet: ReadUpTo) {
+        let message_id = match self
+            .find_message(conn, &packet.room, &packet.message_id)
+            .await
+        {
+            Ok((id, _)) => id,
+            Err((code, error)) => {
+                conn.send_error(code, error, None).await;
+                return;
+            }
+        };
+
+        let (Some(fingerprint), Some(name)) = (conn.fingerprint().await, conn.name().await) else {
+            return;
+        };
+
+        let position = ReadPosition {
+            room: packet.room.clone(),
+            fingerprint: fingerprint.clone(),
+            name: name.clone(),
+            message_id,
+        };
+        match self.dal.advance_read_position(&position).await {
+            Ok(true) => {}
+            // Already read further
+            Ok(false) => return,
+            Err(e) => {
+                eprintln!("failed to store read position: {}", e);
+                let error = "couldn't store the read position";
+                conn.send_error(ErrorCode::Internal, error, None).await;
+                return;
+            }
+        }
+
+        packet.fingerprint = Some(fingerprint);
+        packet.sender_name = Some(name);
+        let room = packet.room.clone();
+        self.broadcast_feature(&room, feature::RECEIPTS, packet)
+            .await;
+    }
+
+    async fn handle_history_request(&self, conn: &Arc<Connection>, request: HistoryRequest) {
+        if !self.is_member(&request.room, conn.address()).await {
+            let error = format!("requested history of room {} without joining", request.room);
+            conn.send_error(ErrorCode::NotInRoom, error, None).await;
+            return;
+        }
+
+        let limit = request.limit.clamp(1, MAX_HISTORY_PAGE) as i32;
+        // The cursor comes from the client, so a malformed one must not take
+        // the server down
+        let page = match self
+            .dal
+            .read_page(&request.room, request.cursor.as_deref(), limit)
+            .await
+        {
+            Ok(page) => page,
+            Err(e) if e.is::<InvalidCursor>() => {
+                conn.send_error(ErrorCode::MalformedPacket, e.to_string(), None)
+                    .await;
+                return;
+            }
+            Err(e) => {
+                eprintln!("failed to read history of {}: {}", request.room, e);
+                let error = "couldn't read the room's history";
+                conn.send_error(ErrorCode::Internal, error, None).await;
+                return;
+            }
+        };
+
+        let messages = page
+            .messages
+            .into_iter()
+            .map(|item| MessagePacket {
+                room: item.room,
+                content: item.content,
+                signature: item.signature,
+                sender: item.sender,
+                sender_name: item.sender_name,
+                id: Some(item.id.to_string()),
+                timestamp: Some(item.timestamp),
+                edited_at: item.edited_at,
+                ttl: None,
+                expires_at: item.expires_at,
+                correlation_id: None,
+            })
+            .collect();
+
+        conn.queue_packet(HistoryPage {
+            room: request.room,
+            messages,
+            cursor: page.cursor,
+        })
+        .await;
+    }
+
+    async fn handle_leave_room(&self, conn: &Arc<Connection>, packet: LeaveRoom) {
+        {
+            let mut rooms = self.rooms.write().await;
+            if let Some(members) = rooms.get_mut(&packet.room) {
+                members.remove(&conn.address());
+                if members.is_empty() {
+                    rooms.remove(&packet.room);
+                }
+            }
+        }
+
+        if let Some(fingerprint) = conn.fingerprint().await {
+            if let Err(e) = self.dal.unsubscribe(&packet.room, &fingerprint).await {
+                eprintln!(
+                    "failed to unsubscribe {} from {}: {}",
+                    fingerprint, packet.room, e
+                );
+            }
+        }
+    }
+
+    async fn handle_queue_ack(&self, conn: &Arc<Connection>, ack: QueueAck) {
+        let Some(fingerprint) = conn.fingerprint().await else {
+            let error = "acked the queue before the handshake";
+            conn.send_error(ErrorCode::NoHandshake, error, None).await;
+            return;
+        };
+
+        let Ok(up_to) = Uuid::parse_str(&ack.up_to) else {
+            let error = format!("invalid queue position {}", ack.up_to);
+            conn.send_error(ErrorCode::MalformedPacket, error, None)
+                .await;
+            return;
+        };
+
+        if let Err(e) = self.dal.dequeue(&fingerprint, up_to).await {
+            eprintln!("failed to clear the queue of {}: {}", fingerprint, e);
+            let error = "couldn't clear the queue";
+            conn.send_error(ErrorCode::Internal, error, None).await;
+            return;
+        }
+
+        conn.finish_queue_batch().await;
+        self.deliver_queue(conn).await;
+    }
+
+    async fn handle_register_name(&self, conn: &Arc<Connection>, packet: RegisterName) {
+        let correlation_id = packet.correlation_id;
+
+        let (Some(fingerprint), Some(public_key)) =
+            (conn.fingerprint().await, conn.public_key_pem().await)
+        else {
+            let error = "tried to register a name without sending its public key";
+            conn.send_error(ErrorCode::NoHandshake, error, correlation_id)
+                .await;
+            return;
+        };
+
+        if !connection::is_valid_name(&packet.name) {
+            let error = format!("invalid display name {}", packet.name);
+            conn.send_error(ErrorCode::InvalidName, error, correlation_id)
+                .await;
+            return;
+        }
+
+        let registered = RegisteredName {
+            name: packet.name.clone(),
+            fingerprint,
+            public_key: public_key.clone(),
+        };
+        match self.dal.register_name(&registered).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let error = format!("name {} is registered to another key", packet.name);
+                conn.send_error(ErrorCode::NameTaken, error, correlation_id)
+                    .await;
+                return;
+            }
+            Err(e) => {
+                eprintln!("failed to register name {}: {}", packet.name, e);
+                let error = "couldn't register the name";
+                conn.send_error(ErrorCode::Internal, error, correlation_id)
+                    .await;
+                return;
+            }
+        }
+
+        conn.set_name(packet.name.clone()).await;
+        conn.send_ack(correlation_id, None).await;
+
+        // Tell everyone else which key the name now belongs to
+        for client in self.map.read().await.values() {
+            if Arc::ptr_eq(client, conn) || !client.has_public_key().await {
+                continue;
+            }
+
+            client
+                .queue_packet(PeerKeyPacket {
+                    public_key: public_key.clone(),
+                    name: packet.name.clone(),
+                })
+                .await;
+        }
+    }
+
+    async fn handle_lookup_name(&self, conn: &Arc<Connection>, packet: LookupName) {
+        let public_key = match self.dal.lookup_name(&packet.name).await {
+            Ok(registered) => registered.map(|r| r.public_key),
+            Err(e) => {
+                eprintln!("failed to look up name {}: {}", packet.name, e);
+                let error = "couldn't look up the name";
+                conn.send_error(ErrorCode::Internal, error, None).await;
+                return;
+            }
+        };
+
+        conn.queue_packet(NameInfo {
+            name: packet.name,
+            public_key,
+        })
+        .await;
+    }
+
+    async fn handle_typing_started(&self, conn: &Arc<Connection>, mut packet: TypingStarted) {
+        if !self.is_member(&packet.room, conn.address()).await {
+            let error = format!("typed in room {} without joining", packet.room);
+            conn.send_error(ErrorCode::NotInRoom, error, None).await;
+            return;
+        }
+
+        if !conn.allow_typing(&packet.room).await {
+            return;
+        }
+
+        packet.fingerprint = conn.fingerprint().await;
+        packet.sender_name = conn.name().await;
+        let room = packet.room.clone();
+        self.broadcast_feature(&room, feature::TYPING, packet).await;
+    }
+
+    async fn handle_typing_stopped(&self, conn: &Arc<Connection>, mut packet: TypingStopped) {
+        if !self.is_member(&packet.room, conn.address()).await {
+            let error = format!("typed in room {} without joining", packet.room);
+            conn.send_error(ErrorCode::NotInRoom, error, None).await;
+            return;
+        }
+
+        conn.stop_typing(&packet.room).await;
+
+        packet.fingerprint = conn.fingerprint().await;
+        let room = packet.room.clone();
+        self.broadcast_feature(&room, feature::TYPING, packet).await;
+    }
+
+    async fn handle_list_members(&self, conn: &Arc<Connection>, _request: ListMembers) {
+        if !conn.has_public_key().await {
+            let error = "tried to list members without sending its public key";
+            conn.send_error(ErrorCode::NoHandshake, error, None).await;
+            return;
+        }
+
+        // Devices sharing a key are one member
+        let mut members: HashMap<String, Member> = HashMap::new();
+        for client in self.map.read().await.values() {
+            let (Some(fingerprint), Some(public_key), Some(name)) = (
+                client.fingerprint().await,
+                client.public_key_pem().await,
+                client.name().await,
+            ) else {
+                continue;
+            };
+
+            members
+                .entry(fingerprint)
+                .or_insert(Member {
+                    public_key,
+                    name,
+                    devices: 0,
+                })
+                .devices += 1;
+        }
+
+        conn.queue_packet(MemberList {
+            members: members.into_values().collect(),
+        })
+        .await;
     }
 
     async fn handle_serverbound_handshake(
@@ -133,10 +1055,123 @@ impl Server {
         sender: &Arc<Connection>,
         handshake: ServerboundHandshake,
     ) {
-        if let Err(e) = sender.set_public_key(handshake.public_key.as_bytes()).await {
-            eprintln!("invalid public key: {}", e);
+        // A registered name can only be claimed with the key it belongs to. An
+        // unparsable key is left for begin_handshake to report.
+        if let Some(fingerprint) = connection::pem_fingerprint(handshake.public_key.as_bytes()) {
+            match self.dal.lookup_name(&handshake.name).await {
+                Ok(Some(owner)) if owner.fingerprint != fingerprint => {
+                    let error = format!("name {} is registered to another key", handshake.name);
+                    sender.send_error(ErrorCode::NameTaken, error, None).await;
+                    return;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("failed to look up name {}: {}", handshake.name, e);
+                    let error = "couldn't check the name";
+                    sender.send_error(ErrorCode::Internal, error, None).await;
+                    return;
+                }
+            }
+        }
+
+        match sender.begin_handshake(&handshake).await {
+            Ok(challenge) => sender.queue_packet(challenge).await,
+            Err(e) => sender.send_error(e.code(), e.to_string(), None).await,
         }
     }
+
+    async fn handle_challenge_response(
+        &self,
+        sender: &Arc<Connection>,
+        response: ServerboundChallengeResponse,
+    ) {
+        if !sender.complete_handshake(&response.signature).await {
+            let error = "challenge response signature mismatch";
+            sender
+                .send_error(ErrorCode::BadSignature, error, None)
+                .await;
+            return;
+        }
+
+        let own_public_key = sender
+            .public_key_pem()
+            .await
+            .expect("public key was just set");
+        let own_name = sender.name().await.expect("name was just set");
+        let own_fingerprint = sender.fingerprint().await.expect("public key was just set");
+
+        // Exchange keys between the new client and everyone already connected
+        // so messages can be encrypted for all of them
+        for client in self.map.read().await.values() {
+            if Arc::ptr_eq(client, sender) {
+                continue;
+            }
+
+            let (Some(public_key), Some(name)) =
+                (client.public_key_pem().await, client.name().await)
+            else {
+                continue;
+            };
+
+            client
+                .queue_packet(PeerKeyPacket {
+                    public_key: own_public_key.clone(),
+                    name: own_name.clone(),
+                })
+                .await;
+            sender
+                .queue_packet(PeerKeyPacket { public_key, name })
+                .await;
+        }
+
+        self.broadcast_presence(sender).await;
+
+        let stored = match self.dal.take_direct_messages(&own_fingerprint).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                eprintln!("failed to read stored direct messages: {}", e);
+                Vec::new()
+            }
+        };
+        for item in stored {
+            sender
+                .queue_packet(DirectMessagePacket {
+                    recipient: item.recipient,
+                    content: item.content,
+                    signature: item.signature,
+                    sender: item.sender,
+                    sender_name: item.sender_name,
+                    correlation_id: None,
+                })
+                .await;
+        }
+
+        self.deliver_queue(sender).await;
+    }
+}
+
+/// Picks the storage backend named by `STORAGE`, defaulting to Cassandra, and
+/// brings its schema up to date
+async fn open_store() -> Arc<dyn MessageStore> {
+    match std::env::var("STORAGE").as_deref() {
+        Ok("memory") => Arc::new(MemoryStore::default()),
+        Ok("sqlite") => {
+            let sqlite_path = std::env::var("SQLITE").expect("SQLITE not set");
+            Arc::new(Sqlite::open(sqlite_path).expect("can't open sqlite database"))
+        }
+        Ok("cassandra") | Err(_) => {
+            let cassandra_address = std::env::var("CASSANDRA").expect("CASSANDRA not set");
+            let cassandra = Cassandra::new(cassandra_address)
+                .await
+                .expect("can't connect to cassandra");
+            cassandra
+                .migrate()
+                .await
+                .expect("failed to migrate cassandra schema");
+            Arc::new(cassandra)
+        }
+        Ok(other) => panic!("unknown STORAGE {}", other),
+    }
 }
 
 #[tokio::main]
@@ -145,19 +1180,22 @@ async fn main() {
         eprintln!(".env was not loaded");
     }
 
+    // `server migrate` only updates the schema
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        open_store().await;
+        println!("Schema is up to date");
+        return;
+    }
+
     let server_address = std::env::var("ADDRESS").expect("ADDRESS not set");
-    let cassandra_address = std::env::var("CASSANDRA").expect("CASSANDRA not set");
 
     let (inbound_msg_send, inbound_msg_recv) = mpsc::channel(64);
 
     let server = Arc::new(Server {
         map: RwLock::new(HashMap::new()),
+        rooms: RwLock::new(HashMap::new()),
         connection: TcpListener::bind(server_address).await.unwrap(),
-        dal: Arc::new(
-            Cassandra::new(cassandra_address)
-                .await
-                .expect("can't connect to cassandra"),
-        ),
+        dal: open_store().await,
         inbound_msg_send,
         inbound_msg_recv: Mutex::new(inbound_msg_recv),
     });
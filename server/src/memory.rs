@@ -0,0 +1,270 @@
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::store::{
+    DirectMessage, InvalidCursor, Message, MessageStore, Page, Queued, ReadPosition,
+    RegisteredName, StoreError, Subscriber,
+};
+
+/// Keeps everything in memory. Nothing survives a restart, which makes it
+/// useful for tests and trying the server without a database.
+#[derive(Default)]
+pub struct MemoryStore {
+    rooms: Mutex<HashMap<String, Vec<StoredMessage>>>,
+    names: Mutex<HashMap<String, RegisteredName>>,
+    /// By room, then fingerprint
+    read_positions: Mutex<HashMap<String, HashMap<String, ReadPosition>>>,
+    /// By room, then fingerprint
+    subscriptions: Mutex<HashMap<String, HashMap<String, Subscriber>>>,
+    /// By recipient, then ID
+    queue: Mutex<HashMap<String, BTreeMap<Uuid, QueuedPacket>>>,
+    /// Insertion counter used as the paging cursor
+    next_seq: AtomicU64,
+}
+
+struct StoredMessage {
+    seq: u64,
+    expires_at: Option<Instant>,
+    message: Message,
+}
+
+impl StoredMessage {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| t <= Instant::now())
+    }
+}
+
+struct QueuedPacket {
+    expires_at: Instant,
+    item: Queued,
+}
+
+impl MemoryStore {
+    fn insert(&self, message: &Message, expires_at: Option<Instant>) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(message.room.clone()).or_default();
+
+        room.retain(|m| !m.is_expired());
+        room.push(StoredMessage {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            expires_at,
+            message: message.clone(),
+        });
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn insert_message(&self, message: &Message) -> Result<(), StoreError> {
+        self.insert(message, None);
+        Ok(())
+    }
+
+    async fn insert_message_ttl(&self, message: &Message, seconds: i32) -> Result<(), StoreError> {
+        let ttl = Duration::from_secs(seconds.max(0) as u64);
+        self.insert(message, Some(Instant::now() + ttl));
+        Ok(())
+    }
+
+    async fn read_page(
+        &self,
+        room: &str,
+        cursor: Option<&str>,
+        limit: i32,
+    ) -> Result<Page, StoreError> {
+        let before = match cursor {
+            Some(c) => c.parse::<u64>().map_err(|_| InvalidCursor)?,
+            None => u64::MAX,
+        };
+
+        let rooms = self.rooms.lock().unwrap();
+        let older: Vec<&StoredMessage> = rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .filter(|m| m.seq < before && !m.is_expired())
+            .collect();
+
+        let start = older.len().saturating_sub(limit.max(0) as usize);
+        let page = &older[start..];
+
+        Ok(Page {
+            messages: page.iter().map(|m| m.message.clone()).collect(),
+            cursor: match page.first() {
+                Some(oldest) if start > 0 => Some(oldest.seq.to_string()),
+                _ => None,
+            },
+        })
+    }
+
+    async fn get_message(&self, room: &str, id: Uuid) -> Result<Option<Message>, StoreError> {
+        let rooms = self.rooms.lock().unwrap();
+        let stored = rooms
+            .get(room)
+            .into_iter()
+            .flatten()
+            .find(|m| m.message.id == id && !m.is_expired());
+
+        Ok(stored.map(|m| m.message.clone()))
+    }
+
+    async fn update_message(
+        &self,
+        room: &str,
+        id: Uuid,
+        content: String,
+        signature: Vec<u8>,
+        edited_at: i64,
+    ) -> Result<(), StoreError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let stored = rooms
+            .get_mut(room)
+            .into_iter()
+            .flatten()
+            .find(|m| m.message.id == id);
+
+        if let Some(stored) = stored {
+            stored.message.content = content;
+            stored.message.signature = signature;
+            stored.message.edited_at = Some(edited_at);
+        }
+
+        Ok(())
+    }
+
+    async fn delete_message(&self, room: &str, id: Uuid) -> Result<(), StoreError> {
+        if let Some(messages) = self.rooms.lock().unwrap().get_mut(room) {
+            messages.retain(|m| m.message.id != id);
+        }
+
+        Ok(())
+    }
+
+    // Nothing outlives the process, so there's never anything left over from
+    // before the delivery queue
+    async fn take_direct_messages(
+        &self,
+        _recipient: &str,
+    ) -> Result<Vec<DirectMessage>, StoreError> {
+        Ok(Vec::new())
+    }
+
+    async fn register_name(&self, name: &RegisteredName) -> Result<bool, StoreError> {
+        let mut names = self.names.lock().unwrap();
+        if let Some(owner) = names.get(&name.name) {
+            if owner.fingerprint != name.fingerprint {
+                return Ok(false);
+            }
+        }
+
+        names.retain(|_, owner| owner.fingerprint != name.fingerprint);
+        names.insert(name.name.clone(), name.clone());
+        Ok(true)
+    }
+
+    async fn lookup_name(&self, name: &str) -> Result<Option<RegisteredName>, StoreError> {
+        Ok(self.names.lock().unwrap().get(name).cloned())
+    }
+
+    async fn advance_read_position(&self, position: &ReadPosition) -> Result<bool, StoreError> {
+        let mut read_positions = self.read_positions.lock().unwrap();
+        let room = read_positions.entry(position.room.clone()).or_default();
+
+        match room.get(&position.fingerprint) {
+            Some(current) if current.message_id >= position.message_id => Ok(false),
+            _ => {
+                room.insert(position.fingerprint.clone(), position.clone());
+                Ok(true)
+            }
+        }
+    }
+
+    async fn read_positions(&self, room: &str) -> Result<Vec<ReadPosition>, StoreError> {
+        let read_positions = self.read_positions.lock().unwrap();
+        let positions = read_positions
+            .get(room)
+            .into_iter()
+            .flat_map(|r| r.values());
+
+        Ok(positions.cloned().collect())
+    }
+
+    async fn subscribe(&self, room: &str, subscriber: &Subscriber) -> Result<(), StoreError> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .entry(room.to_owned())
+            .or_default()
+            .insert(subscriber.fingerprint.clone(), subscriber.clone());
+
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, room: &str, fingerprint: &str) -> Result<(), StoreError> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        if let Some(subscribers) = subscriptions.get_mut(room) {
+            subscribers.remove(fingerprint);
+            if subscribers.is_empty() {
+                subscriptions.remove(room);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn subscribers(&self, room: &str) -> Result<Vec<Subscriber>, StoreError> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        let subscribers = subscriptions.get(room).into_iter().flat_map(|s| s.values());
+
+        Ok(subscribers.cloned().collect())
+    }
+
+    async fn enqueue(&self, item: &Queued, seconds: i32) -> Result<(), StoreError> {
+        let mut queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        queue.retain(|_, queued| {
+            queued.retain(|_, packet| packet.expires_at > now);
+            !queued.is_empty()
+        });
+
+        let packet = QueuedPacket {
+            expires_at: now + Duration::from_secs(seconds.max(0) as u64),
+            item: item.clone(),
+        };
+        queue
+            .entry(item.recipient.clone())
+            .or_default()
+            .insert(item.id, packet);
+
+        Ok(())
+    }
+
+    async fn queued(&self, recipient: &str, limit: i32) -> Result<Vec<Queued>, StoreError> {
+        let queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        let queued = queue
+            .get(recipient)
+            .into_iter()
+            .flat_map(|q| q.values())
+            .filter(|packet| packet.expires_at > now)
+            .map(|packet| packet.item.clone());
+
+        Ok(queued.take(limit.max(0) as usize).collect())
+    }
+
+    async fn dequeue(&self, recipient: &str, up_to: Uuid) -> Result<(), StoreError> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(queued) = queue.get_mut(recipient) {
+            queued.retain(|id, _| *id > up_to);
+            if queued.is_empty() {
+                queue.remove(recipient);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! Versioned CQL schema for the Cassandra backend. Migrations are applied in
+//! order and each version is recorded in `eteedir.schema_version`, so only new
+//! ones run on startup. Never edit a migration that has shipped; add a new one.
+
+pub struct Migration {
+    pub version: i32,
+    pub statements: &'static [&'static str],
+}
+
+/// Run before anything else so the version table can be read
+pub const BOOTSTRAP: &[&str] = &[
+    "CREATE KEYSPACE IF NOT EXISTS eteedir WITH replication = {'class': 'SimpleStrategy', 'replication_factor': 1}",
+    "CREATE TABLE IF NOT EXISTS eteedir.schema_version (version int PRIMARY KEY, applied_at timestamp)",
+];
+
+/// Version that creates `room_messages`
+pub const ROOM_MESSAGES: i32 = 3;
+
+pub const MIGRATIONS: &[Migration] = &[
+    // The tables deployments had before there were migrations, so this is a
+    // no-op for them
+    Migration {
+        version: 1,
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS eteedir.messages (
+                id bigint,
+                timestamp timestamp,
+                message text,
+                signature blob,
+                PRIMARY KEY ((id), timestamp)
+            )",
+            "CREATE TABLE IF NOT EXISTS eteedir.user (id bigint PRIMARY KEY, username text)",
+        ],
+    },
+    Migration {
+        version: 2,
+        statements: &[
+            "ALTER TABLE eteedir.messages ADD room text",
+            "ALTER TABLE eteedir.messages ADD sender text",
+            "ALTER TABLE eteedir.messages ADD sender_name text",
+            "CREATE TABLE IF NOT EXISTS eteedir.direct_messages (
+                recipient text,
+                timestamp timestamp,
+                id bigint,
+                message text,
+                signature blob,
+                sender text,
+                sender_name text,
+                PRIMARY KEY ((recipient), timestamp, id)
+            ) WITH CLUSTERING ORDER BY (timestamp ASC, id ASC)",
+        ],
+    },
+    // Room messages are clustered by their server-assigned UUIDv7, which sorts
+    // by time. CQL can't copy rows between tables, so `Cassandra::migrate`
+    // copies the history in the old `messages` table over after this runs.
+    Migration {
+        version: ROOM_MESSAGES,
+        statements: &["CREATE TABLE IF NOT EXISTS eteedir.room_messages (
+                room text,
+                id uuid,
+                timestamp timestamp,
+                message text,
+                signature blob,
+                sender text,
+                sender_name text,
+                PRIMARY KEY ((room), id)
+            ) WITH CLUSTERING ORDER BY (id ASC)"],
+    },
+    Migration {
+        version: 4,
+        statements: &["ALTER TABLE eteedir.room_messages ADD edited_at timestamp"],
+    },
+    Migration {
+        version: 5,
+        statements: &["ALTER TABLE eteedir.room_messages ADD expires_at timestamp"],
+    },
+    // Names are registered with lightweight transactions on the partition key,
+    // which the old random-ID `user` table couldn't do. Nothing ever wrote to
+    // it, so it's recreated rather than migrated.
+    Migration {
+        version: 6,
+        statements: &[
+            "DROP TABLE IF EXISTS eteedir.user",
+            "CREATE TABLE eteedir.user (
+                username text PRIMARY KEY,
+                fingerprint text,
+                public_key text
+            )",
+            "CREATE TABLE IF NOT EXISTS eteedir.user_by_key (
+                fingerprint text PRIMARY KEY,
+                username text
+            )",
+        ],
+    },
+    Migration {
+        version: 7,
+        statements: &["CREATE TABLE IF NOT EXISTS eteedir.read_positions (
+                room text,
+                fingerprint text,
+                name text,
+                message_id uuid,
+                PRIMARY KEY ((room), fingerprint)
+            )"],
+    },
+    // Clustered by UUIDv7 so a recipient's queue reads back oldest first
+    Migration {
+        version: 8,
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS eteedir.subscriptions (
+                room text,
+                fingerprint text,
+                name text,
+                public_key text,
+                PRIMARY KEY ((room), fingerprint)
+            )",
+            "CREATE TABLE IF NOT EXISTS eteedir.delivery_queue (
+                recipient text,
+                id uuid,
+                kind text,
+                packet text,
+                PRIMARY KEY ((recipient), id)
+            ) WITH CLUSTERING ORDER BY (id ASC)",
+        ],
+    },
+];
@@ -0,0 +1,476 @@
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::path::Path;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use crate::store::{
+    DirectMessage, InvalidCursor, Message, MessageStore, Page, Queued, ReadPosition,
+    RegisteredName, StoreError, Subscriber,
+};
+
+/// Schema changes in order. The database's `user_version` is the number that
+/// have been applied. Never edit one that has shipped; add a new one.
+const MIGRATIONS: &[&str] = &[
+    "
+CREATE TABLE IF NOT EXISTS messages (
+    room TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    signature BLOB NOT NULL,
+    sender TEXT,
+    sender_name TEXT,
+    timestamp TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    expires_at INTEGER,
+    PRIMARY KEY (room, id)
+);
+
+CREATE TABLE IF NOT EXISTS direct_messages (
+    recipient TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    signature BLOB NOT NULL,
+    sender TEXT,
+    sender_name TEXT,
+    timestamp TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    PRIMARY KEY (recipient, id)
+);
+",
+    // Room messages get UUIDv7 IDs and millisecond timestamps. Older rows get
+    // random IDs since theirs weren't UUIDs.
+    "
+CREATE TABLE room_messages (
+    room TEXT NOT NULL,
+    id BLOB NOT NULL,
+    timestamp INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    signature BLOB NOT NULL,
+    sender TEXT,
+    sender_name TEXT,
+    expires_at INTEGER,
+    PRIMARY KEY (room, id)
+);
+
+INSERT INTO room_messages
+SELECT room, randomblob(16), CAST((julianday(timestamp) - 2440587.5) * 86400000 AS INTEGER),
+    message, signature, sender, sender_name, expires_at
+FROM messages ORDER BY rowid;
+
+DROP TABLE messages;
+ALTER TABLE room_messages RENAME TO messages;
+",
+    "ALTER TABLE messages ADD COLUMN edited_at INTEGER;",
+    // A key has at most one name
+    "
+CREATE TABLE users (
+    username TEXT PRIMARY KEY,
+    fingerprint TEXT NOT NULL UNIQUE,
+    public_key TEXT NOT NULL
+);
+",
+    "
+CREATE TABLE read_positions (
+    room TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    name TEXT NOT NULL,
+    message_id BLOB NOT NULL,
+    PRIMARY KEY (room, fingerprint)
+);
+",
+    "
+CREATE TABLE subscriptions (
+    room TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    name TEXT NOT NULL,
+    public_key TEXT NOT NULL,
+    PRIMARY KEY (room, fingerprint)
+);
+
+CREATE TABLE delivery_queue (
+    recipient TEXT NOT NULL,
+    id BLOB NOT NULL,
+    kind TEXT NOT NULL,
+    packet TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    PRIMARY KEY (recipient, id)
+);
+",
+];
+
+/// Columns read by [`message_from_row`], in order. `expires_at` is stored in
+/// seconds to compare against `strftime('%s')`.
+const MESSAGE_COLUMNS: &str =
+    "room, id, timestamp, message, signature, sender, sender_name, edited_at, expires_at * 1000";
+
+/// Condition matching messages and queued packets whose TTL hasn't run out
+const NOT_EXPIRED: &str =
+    "(expires_at IS NULL OR expires_at > CAST(strftime('%s', 'now') AS INTEGER))";
+
+/// Embedded storage for single-node deployments
+pub struct Sqlite {
+    // rusqlite is synchronous, so every query blocks the tokio worker thread
+    // it runs on while holding this lock. That's fine for the small,
+    // single-node deployments this backend is meant for; use Cassandra when
+    // the server has to scale.
+    connection: Mutex<Connection>,
+}
+
+impl Sqlite {
+    /// Opens or creates the database at `path` and applies any migrations it
+    /// hasn't seen yet
+    pub fn open(path: impl AsRef<Path>) -> Result<Sqlite, StoreError> {
+        let mut connection = Connection::open(path)?;
+
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", applied + 1)?;
+            transaction.commit()?;
+        }
+
+        Ok(Sqlite {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn insert(&self, message: &Message, ttl: Option<i32>) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.execute(
+            &format!("DELETE FROM messages WHERE NOT {}", NOT_EXPIRED),
+            [],
+        )?;
+        connection.execute(
+            "INSERT INTO messages \
+             (room, id, timestamp, message, signature, sender, sender_name, expires_at) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, CAST(strftime('%s', 'now') AS INTEGER) + ?8)",
+            params![
+                message.room,
+                message.id,
+                message.timestamp,
+                message.content,
+                message.signature,
+                message.sender,
+                message.sender_name,
+                ttl,
+            ],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageStore for Sqlite {
+    async fn insert_message(&self, message: &Message) -> Result<(), StoreError> {
+        self.insert(message, None)
+    }
+
+    async fn insert_message_ttl(&self, message: &Message, seconds: i32) -> Result<(), StoreError> {
+        self.insert(message, Some(seconds))
+    }
+
+    // Pages are keyed by rowid, which increases with every insert
+    async fn read_page(
+        &self,
+        room: &str,
+        cursor: Option<&str>,
+        limit: i32,
+    ) -> Result<Page, StoreError> {
+        let before = match cursor {
+            Some(c) => c.parse::<i64>().map_err(|_| InvalidCursor)?,
+            None => i64::MAX,
+        };
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT rowid, {} FROM messages \
+             WHERE room = ?1 AND rowid < ?2 AND {} ORDER BY rowid DESC LIMIT ?3",
+            MESSAGE_COLUMNS, NOT_EXPIRED
+        ))?;
+
+        // Fetch one extra row to find out if there's an older page
+        let mut rows = statement
+            .query_map(params![room, before, limit + 1], |row| {
+                Ok((row.get::<_, i64>(0)?, message_from_row(row, 1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let has_more = rows.len() > limit as usize;
+        rows.truncate(limit as usize);
+        rows.reverse();
+
+        let cursor = match rows.first() {
+            Some((rowid, _)) if has_more => Some(rowid.to_string()),
+            _ => None,
+        };
+
+        Ok(Page {
+            messages: rows.into_iter().map(|(_, m)| m).collect(),
+            cursor,
+        })
+    }
+
+    async fn get_message(&self, room: &str, id: Uuid) -> Result<Option<Message>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {} FROM messages WHERE room = ?1 AND id = ?2 AND {}",
+            MESSAGE_COLUMNS, NOT_EXPIRED
+        ))?;
+
+        let message = statement
+            .query_map(params![room, id], |row| message_from_row(row, 0))?
+            .next()
+            .transpose()?;
+
+        Ok(message)
+    }
+
+    async fn update_message(
+        &self,
+        room: &str,
+        id: Uuid,
+        content: String,
+        signature: Vec<u8>,
+        edited_at: i64,
+    ) -> Result<(), StoreError> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE messages SET message = ?1, signature = ?2, edited_at = ?3 \
+             WHERE room = ?4 AND id = ?5",
+            params![content, signature, edited_at, room, id],
+        )?;
+
+        Ok(())
+    }
+
+    async fn delete_message(&self, room: &str, id: Uuid) -> Result<(), StoreError> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM messages WHERE room = ?1 AND id = ?2",
+            params![room, id],
+        )?;
+
+        Ok(())
+    }
+
+    async fn take_direct_messages(
+        &self,
+        recipient: &str,
+    ) -> Result<Vec<DirectMessage>, StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        let messages = transaction
+            .prepare(
+                "SELECT recipient, message, signature, sender, sender_name FROM direct_messages \
+                 WHERE recipient = ?1 ORDER BY timestamp, rowid",
+            )?
+            .query_map(params![recipient], |row| {
+                Ok(DirectMessage {
+                    recipient: row.get(0)?,
+                    content: row.get(1)?,
+                    signature: row.get(2)?,
+                    sender: row.get(3)?,
+                    sender_name: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        transaction.execute(
+            "DELETE FROM direct_messages WHERE recipient = ?1",
+            params![recipient],
+        )?;
+        transaction.commit()?;
+
+        Ok(messages)
+    }
+
+    async fn register_name(&self, name: &RegisteredName) -> Result<bool, StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        let owner: Option<String> = transaction
+            .query_row(
+                "SELECT fingerprint FROM users WHERE username = ?1",
+                params![name.name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if owner.is_some_and(|owner| owner != name.fingerprint) {
+            return Ok(false);
+        }
+
+        transaction.execute(
+            "DELETE FROM users WHERE fingerprint = ?1",
+            params![name.fingerprint],
+        )?;
+        transaction.execute(
+            "INSERT INTO users (username, fingerprint, public_key) VALUES (?1, ?2, ?3)",
+            params![name.name, name.fingerprint, name.public_key],
+        )?;
+        transaction.commit()?;
+
+        Ok(true)
+    }
+
+    async fn lookup_name(&self, name: &str) -> Result<Option<RegisteredName>, StoreError> {
+        let registered = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT username, fingerprint, public_key FROM users WHERE username = ?1",
+                params![name],
+                |row| {
+                    Ok(RegisteredName {
+                        name: row.get(0)?,
+                        fingerprint: row.get(1)?,
+                        public_key: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(registered)
+    }
+
+    // UUIDv7 bytes compare in time order, so the newer position wins
+    async fn advance_read_position(&self, position: &ReadPosition) -> Result<bool, StoreError> {
+        let changed = self.connection.lock().unwrap().execute(
+            "INSERT INTO read_positions (room, fingerprint, name, message_id) \
+             VALUES (?1, ?2, ?3, ?4) \
+             ON CONFLICT (room, fingerprint) DO UPDATE \
+             SET name = excluded.name, message_id = excluded.message_id \
+             WHERE excluded.message_id > read_positions.message_id",
+            params![
+                position.room,
+                position.fingerprint,
+                position.name,
+                position.message_id,
+            ],
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    async fn read_positions(&self, room: &str) -> Result<Vec<ReadPosition>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let positions = connection
+            .prepare(
+                "SELECT room, fingerprint, name, message_id FROM read_positions WHERE room = ?1",
+            )?
+            .query_map(params![room], |row| {
+                Ok(ReadPosition {
+                    room: row.get(0)?,
+                    fingerprint: row.get(1)?,
+                    name: row.get(2)?,
+                    message_id: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(positions)
+    }
+
+    async fn subscribe(&self, room: &str, subscriber: &Subscriber) -> Result<(), StoreError> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO subscriptions (room, fingerprint, name, public_key) \
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                room,
+                subscriber.fingerprint,
+                subscriber.name,
+                subscriber.public_key
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, room: &str, fingerprint: &str) -> Result<(), StoreError> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM subscriptions WHERE room = ?1 AND fingerprint = ?2",
+            params![room, fingerprint],
+        )?;
+
+        Ok(())
+    }
+
+    async fn subscribers(&self, room: &str) -> Result<Vec<Subscriber>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let subscribers = connection
+            .prepare("SELECT fingerprint, name, public_key FROM subscriptions WHERE room = ?1")?
+            .query_map(params![room], |row| {
+                Ok(Subscriber {
+                    fingerprint: row.get(0)?,
+                    name: row.get(1)?,
+                    public_key: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(subscribers)
+    }
+
+    async fn enqueue(&self, item: &Queued, seconds: i32) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+
+        connection.execute(
+            &format!("DELETE FROM delivery_queue WHERE NOT {}", NOT_EXPIRED),
+            [],
+        )?;
+        connection.execute(
+            "INSERT INTO delivery_queue (recipient, id, kind, packet, expires_at) \
+             VALUES (?1, ?2, ?3, ?4, CAST(strftime('%s', 'now') AS INTEGER) + ?5)",
+            params![item.recipient, item.id, item.kind, item.packet, seconds],
+        )?;
+
+        Ok(())
+    }
+
+    // UUIDv7 bytes sort in time order
+    async fn queued(&self, recipient: &str, limit: i32) -> Result<Vec<Queued>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let queued = connection
+            .prepare(&format!(
+                "SELECT recipient, id, kind, packet FROM delivery_queue \
+                 WHERE recipient = ?1 AND {} ORDER BY id LIMIT ?2",
+                NOT_EXPIRED
+            ))?
+            .query_map(params![recipient, limit], |row| {
+                Ok(Queued {
+                    recipient: row.get(0)?,
+                    id: row.get(1)?,
+                    kind: row.get(2)?,
+                    packet: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(queued)
+    }
+
+    async fn dequeue(&self, recipient: &str, up_to: Uuid) -> Result<(), StoreError> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM delivery_queue WHERE recipient = ?1 AND id <= ?2",
+            params![recipient, up_to],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Reads a [`Message`] from [`MESSAGE_COLUMNS`] starting at column `first`
+fn message_from_row(row: &Row, first: usize) -> rusqlite::Result<Message> {
+    Ok(Message {
+        room: row.get(first)?,
+        id: row.get(first + 1)?,
+        timestamp: row.get(first + 2)?,
+        content: row.get(first + 3)?,
+        signature: row.get(first + 4)?,
+        sender: row.get(first + 5)?,
+        sender_name: row.get(first + 6)?,
+        edited_at: row.get(first + 7)?,
+        expires_at: row.get(first + 8)?,
+    })
+}
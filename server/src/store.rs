@@ -0,0 +1,173 @@
+use async_trait::async_trait;
+use scylla::FromRow;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use uuid::Uuid;
+
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+/// Returned by [`MessageStore::read_page`] for a cursor the store didn't hand
+/// out
+#[derive(Debug)]
+pub struct InvalidCursor;
+
+impl fmt::Display for InvalidCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid history cursor")
+    }
+}
+
+impl Error for InvalidCursor {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
+pub struct Message {
+    pub room: String,
+    /// UUIDv7, so sorting by ID sorts by time
+    pub id: Uuid,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: i64,
+    pub content: String,
+    pub signature: Vec<u8>,
+    /// PEM public key of the author
+    pub sender: Option<String>,
+    /// Display name the author had when sending the message
+    pub sender_name: Option<String>,
+    /// When `content` was last replaced, in milliseconds since the Unix epoch
+    pub edited_at: Option<i64>,
+    /// When a message stored with a TTL disappears, in milliseconds since the
+    /// Unix epoch
+    pub expires_at: Option<i64>,
+}
+
+/// A direct message stored by a server from before the delivery queue
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
+pub struct DirectMessage {
+    /// Fingerprint of the recipient's public key
+    pub recipient: String,
+    pub content: String,
+    pub signature: Vec<u8>,
+    pub sender: Option<String>,
+    pub sender_name: Option<String>,
+}
+
+/// A display name and the key it's registered to
+#[derive(Debug, Clone, FromRow)]
+pub struct RegisteredName {
+    pub name: String,
+    /// Fingerprint of `public_key`
+    pub fingerprint: String,
+    /// PEM public key
+    pub public_key: String,
+}
+
+/// The newest message a key has read in a room
+#[derive(Debug, Clone, FromRow)]
+pub struct ReadPosition {
+    pub room: String,
+    pub fingerprint: String,
+    /// Display name the reader had when they last read the room
+    pub name: String,
+    pub message_id: Uuid,
+}
+
+/// A key subscribed to a room
+#[derive(Debug, Clone, FromRow)]
+pub struct Subscriber {
+    pub fingerprint: String,
+    /// Display name the key had when it last joined the room
+    pub name: String,
+    /// PEM public key, so messages can be encrypted for it while it's offline
+    pub public_key: String,
+}
+
+/// A packet waiting to be delivered to a key that was offline when it was sent
+#[derive(Debug, Clone, FromRow)]
+pub struct Queued {
+    /// Fingerprint of the key it's for
+    pub recipient: String,
+    /// UUIDv7, so the queue is delivered in the order it was filled
+    pub id: Uuid,
+    /// [`Packet::ID`](common::Packet::ID) of the packet
+    pub kind: String,
+    /// The packet's JSON
+    pub packet: String,
+}
+
+/// A slice of a room's history
+pub struct Page {
+    /// Oldest first
+    pub messages: Vec<Message>,
+    /// Opaque position to pass to [`MessageStore::read_page`] for the previous
+    /// (older) page, or `None` if this page reaches the start of the room
+    pub cursor: Option<String>,
+}
+
+/// Persistent storage for room and direct messages, registered names, read
+/// positions and the delivery queue
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    async fn insert_message(&self, message: &Message) -> Result<(), StoreError>;
+
+    /// Stores a message that is deleted after `seconds`
+    async fn insert_message_ttl(&self, message: &Message, seconds: i32) -> Result<(), StoreError>;
+
+    /// Up to `limit` messages of `room` that are older than `cursor`, or the
+    /// newest ones if there's no cursor. Fails with [`InvalidCursor`] if the
+    /// cursor can't be parsed.
+    async fn read_page(
+        &self,
+        room: &str,
+        cursor: Option<&str>,
+        limit: i32,
+    ) -> Result<Page, StoreError>;
+
+    async fn get_message(&self, room: &str, id: Uuid) -> Result<Option<Message>, StoreError>;
+
+    /// Replaces the content and signature of a message and sets `edited_at`
+    async fn update_message(
+        &self,
+        room: &str,
+        id: Uuid,
+        content: String,
+        signature: Vec<u8>,
+        edited_at: i64,
+    ) -> Result<(), StoreError>;
+
+    async fn delete_message(&self, room: &str, id: Uuid) -> Result<(), StoreError>;
+
+    /// Removes and returns every direct message stored for `recipient`. New
+    /// direct messages go through the delivery queue instead.
+    async fn take_direct_messages(&self, recipient: &str)
+        -> Result<Vec<DirectMessage>, StoreError>;
+
+    /// Registers `name` to a key, releasing the name the key had before.
+    /// Returns false without changing anything if the name belongs to another
+    /// key.
+    async fn register_name(&self, name: &RegisteredName) -> Result<bool, StoreError>;
+
+    async fn lookup_name(&self, name: &str) -> Result<Option<RegisteredName>, StoreError>;
+
+    /// Stores a read position if it's newer than the one already stored for
+    /// that key and room. Returns whether it was.
+    async fn advance_read_position(&self, position: &ReadPosition) -> Result<bool, StoreError>;
+
+    async fn read_positions(&self, room: &str) -> Result<Vec<ReadPosition>, StoreError>;
+
+    /// Remembers that a key is in a room, so it's queued what the room is sent
+    /// while it's offline
+    async fn subscribe(&self, room: &str, subscriber: &Subscriber) -> Result<(), StoreError>;
+
+    async fn unsubscribe(&self, room: &str, fingerprint: &str) -> Result<(), StoreError>;
+
+    async fn subscribers(&self, room: &str) -> Result<Vec<Subscriber>, StoreError>;
+
+    /// Queues a packet that's dropped after `seconds` if it isn't delivered
+    async fn enqueue(&self, item: &Queued, seconds: i32) -> Result<(), StoreError>;
+
+    /// Up to `limit` of the oldest packets queued for `recipient`
+    async fn queued(&self, recipient: &str, limit: i32) -> Result<Vec<Queued>, StoreError>;
+
+    /// Removes the packets queued for `recipient` up to and including `up_to`
+    async fn dequeue(&self, recipient: &str, up_to: Uuid) -> Result<(), StoreError>;
+}
@@ -0,0 +1,221 @@
+//! Tests that run against every [`MessageStore`] backend that doesn't need a
+//! database server
+
+use crate::memory::MemoryStore;
+use crate::sqlite::Sqlite;
+use crate::store::{InvalidCursor, Message, MessageStore, Queued, RegisteredName};
+use uuid::Uuid;
+
+fn backends() -> Vec<(&'static str, Box<dyn MessageStore>)> {
+    vec![
+        ("memory", Box::new(MemoryStore::default())),
+        ("sqlite", Box::new(Sqlite::open(":memory:").unwrap())),
+    ]
+}
+
+fn message(room: &str, content: &str) -> Message {
+    Message {
+        room: room.to_string(),
+        id: Uuid::now_v7(),
+        timestamp: 0,
+        content: content.to_string(),
+        signature: vec![1; 256],
+        sender: Some("key".to_string()),
+        sender_name: Some("alice".to_string()),
+        edited_at: None,
+        expires_at: None,
+    }
+}
+
+fn contents(messages: &[Message]) -> Vec<&str> {
+    messages.iter().map(|m| m.content.as_str()).collect()
+}
+
+#[tokio::test]
+async fn messages_are_read_back_per_room_oldest_first() {
+    for (name, store) in backends() {
+        for content in ["one", "two", "three"] {
+            store
+                .insert_message(&message("general", content))
+                .await
+                .unwrap();
+        }
+        store
+            .insert_message(&message("random", "other"))
+            .await
+            .unwrap();
+
+        let general = store.read_page("general", None, 10).await.unwrap();
+        assert_eq!(
+            contents(&general.messages),
+            ["one", "two", "three"],
+            "{}",
+            name
+        );
+        assert_eq!(general.messages[0].sender_name.as_deref(), Some("alice"));
+        assert_eq!(general.cursor, None, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn pages_walk_back_from_the_newest_message() {
+    for (name, store) in backends() {
+        for content in ["one", "two", "three"] {
+            store
+                .insert_message(&message("general", content))
+                .await
+                .unwrap();
+        }
+
+        let newest = store.read_page("general", None, 2).await.unwrap();
+        assert_eq!(contents(&newest.messages), ["two", "three"], "{}", name);
+
+        let cursor = newest.cursor.expect("there's an older page");
+        let oldest = store.read_page("general", Some(&cursor), 2).await.unwrap();
+        assert_eq!(contents(&oldest.messages), ["one"], "{}", name);
+        assert_eq!(oldest.cursor, None, "{}", name);
+
+        let error = store.read_page("general", Some("nope"), 2).await.err();
+        assert!(error.is_some_and(|e| e.is::<InvalidCursor>()), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn messages_are_updated_and_deleted_by_id() {
+    for (name, store) in backends() {
+        let first = message("general", "first");
+        let second = message("general", "second");
+        store.insert_message(&first).await.unwrap();
+        store.insert_message(&second).await.unwrap();
+
+        store
+            .update_message("general", first.id, "edited".to_string(), vec![2; 256], 5)
+            .await
+            .unwrap();
+        store.delete_message("general", second.id).await.unwrap();
+
+        let page = store.read_page("general", None, 10).await.unwrap();
+        assert_eq!(contents(&page.messages), ["edited"], "{}", name);
+        assert_eq!(page.messages[0].id, first.id, "{}", name);
+
+        let edited = store.get_message("general", first.id).await.unwrap();
+        assert_eq!(edited.unwrap().edited_at, Some(5), "{}", name);
+        let deleted = store.get_message("general", second.id).await.unwrap();
+        assert!(deleted.is_none(), "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn expired_messages_are_not_read() {
+    for (name, store) in backends() {
+        store
+            .insert_message_ttl(&message("general", "gone"), 0)
+            .await
+            .unwrap();
+        store
+            .insert_message_ttl(&message("general", "kept"), 3600)
+            .await
+            .unwrap();
+
+        let page = store.read_page("general", None, 10).await.unwrap();
+        assert_eq!(contents(&page.messages), ["kept"], "{}", name);
+    }
+}
+
+fn registered(name: &str, fingerprint: &str) -> RegisteredName {
+    RegisteredName {
+        name: name.to_string(),
+        fingerprint: fingerprint.to_string(),
+        public_key: format!("{} key", fingerprint),
+    }
+}
+
+#[tokio::test]
+async fn names_belong_to_one_key_at_a_time() {
+    for (name, store) in backends() {
+        assert!(store
+            .register_name(&registered("alice", "a"))
+            .await
+            .unwrap());
+        assert!(!store
+            .register_name(&registered("alice", "b"))
+            .await
+            .unwrap());
+
+        let alice = store.lookup_name("alice").await.unwrap().unwrap();
+        assert_eq!(alice.fingerprint, "a", "{}", name);
+        assert_eq!(alice.public_key, "a key", "{}", name);
+
+        // Taking a new name releases the old one
+        assert!(store.register_name(&registered("ally", "a")).await.unwrap());
+        assert!(
+            store.lookup_name("alice").await.unwrap().is_none(),
+            "{}",
+            name
+        );
+        assert!(store
+            .register_name(&registered("alice", "b"))
+            .await
+            .unwrap());
+
+        let alice = store.lookup_name("alice").await.unwrap().unwrap();
+        assert_eq!(alice.fingerprint, "b", "{}", name);
+        assert_eq!(
+            store
+                .lookup_name("ally")
+                .await
+                .unwrap()
+                .unwrap()
+                .fingerprint,
+            "a",
+            "{}",
+            name
+        );
+    }
+}
+
+#[tokio::test]
+async fn queue_is_read_oldest_first_and_acked_up_to_an_id() {
+    for (name, store) in backends() {
+        let queued: Vec<Queued> = ["first", "second", "third"]
+            .into_iter()
+            .map(|packet| Queued {
+                recipient: "bob".to_string(),
+                id: Uuid::now_v7(),
+                kind: "message".to_string(),
+                packet: packet.to_string(),
+            })
+            .collect();
+        for item in &queued {
+            store.enqueue(item, 3600).await.unwrap();
+        }
+
+        let batch = store.queued("bob", 2).await.unwrap();
+        let packets: Vec<&str> = batch.iter().map(|q| q.packet.as_str()).collect();
+        assert_eq!(packets, ["first", "second"], "{}", name);
+
+        store.dequeue("bob", batch[1].id).await.unwrap();
+        let rest = store.queued("bob", 2).await.unwrap();
+        assert_eq!(rest.len(), 1, "{}", name);
+        assert_eq!(rest[0].id, queued[2].id, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn expired_packets_are_not_read_from_the_queue() {
+    for (name, store) in backends() {
+        for (packet, seconds) in [("gone", 0), ("kept", 3600)] {
+            let item = Queued {
+                recipient: "bob".to_string(),
+                id: Uuid::now_v7(),
+                kind: "message".to_string(),
+                packet: packet.to_string(),
+            };
+            store.enqueue(&item, seconds).await.unwrap();
+        }
+
+        let queued = store.queued("bob", 10).await.unwrap();
+        let packets: Vec<&str> = queued.iter().map(|q| q.packet.as_str()).collect();
+        assert_eq!(packets, ["kept"], "{}", name);
+    }
+}
@@ -0,0 +1,416 @@
+//! End-to-end tests that run the server on [`MemoryStore`] and talk to it over
+//! real WebSockets. Packets are written and read as JSON so the tests behave
+//! like a client built from an older `common`.
+
+use super::*;
+use common::{ClientboundChallenge, ErrorPacket};
+use futures_util::{SinkExt, StreamExt};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::rsa::Rsa;
+use openssl::sign::Signer;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+/// Longest a test waits for the server to do something
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts a server on a free local port
+async fn start_server() -> (Arc<Server>, SocketAddr) {
+    let (inbound_msg_send, inbound_msg_recv) = mpsc::channel(64);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let server = Arc::new(Server {
+        map: RwLock::new(HashMap::new()),
+        rooms: RwLock::new(HashMap::new()),
+        connection: listener,
+        dal: Arc::new(MemoryStore::default()),
+        inbound_msg_send,
+        inbound_msg_recv: Mutex::new(inbound_msg_recv),
+    });
+
+    tokio::spawn(server.clone().accept_loop());
+    let running = server.clone();
+    tokio::spawn(async move { running.run().await });
+
+    (server, address)
+}
+
+/// Polls until `condition` holds, failing the test after [`TIMEOUT`]
+async fn wait_until<F, Fut>(mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let waited = tokio::time::timeout(TIMEOUT, async {
+        while !condition().await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+
+    assert!(waited.is_ok(), "timed out waiting for the server");
+}
+
+struct TestClient {
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    key: PKey<Private>,
+}
+
+impl TestClient {
+    async fn connect(address: SocketAddr, key: PKey<Private>) -> TestClient {
+        let (socket, _) = connect_async(format!("ws://{}", address)).await.unwrap();
+        TestClient { socket, key }
+    }
+
+    fn generate_key() -> PKey<Private> {
+        PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap()
+    }
+
+    /// Connects and answers the challenge as `name`, speaking protocol
+    /// version 1
+    async fn handshake(address: SocketAddr, name: &str) -> TestClient {
+        let key = TestClient::generate_key();
+        TestClient::handshake_with(address, key, json!({ "name": name })).await
+    }
+
+    /// Connects as `name` with the current protocol version and `features`
+    async fn handshake_with_features(
+        address: SocketAddr,
+        name: &str,
+        features: &[&str],
+    ) -> TestClient {
+        let handshake = json!({
+            "name": name,
+            "protocol_version": common::PROTOCOL_VERSION,
+            "features": features,
+        });
+        TestClient::handshake_with(address, TestClient::generate_key(), handshake).await
+    }
+
+    /// Sends `handshake` with the public half of `key` added and answers the
+    /// challenge
+    async fn handshake_with(
+        address: SocketAddr,
+        key: PKey<Private>,
+        mut handshake: Value,
+    ) -> TestClient {
+        let mut client = TestClient::connect(address, key).await;
+
+        let public_key = String::from_utf8(client.key.public_key_to_pem().unwrap()).unwrap();
+        handshake["public_key"] = public_key.into();
+        client.send(ServerboundHandshake::ID, handshake).await;
+
+        let challenge = client.expect(ClientboundChallenge::ID).await;
+        let signature = client.sign(challenge["nonce"].as_str().unwrap());
+        client
+            .send(
+                ServerboundChallengeResponse::ID,
+                json!({ "signature": signature }),
+            )
+            .await;
+
+        client
+    }
+
+    async fn send(&mut self, id: &str, packet: Value) {
+        let frame = format!("{}|{}", id, packet);
+        self.socket.send(Message::Text(frame)).await.unwrap();
+    }
+
+    /// Base64 signature of `data` with this client's key
+    fn sign(&self, data: &str) -> String {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).unwrap();
+        signer.update(data.as_bytes()).unwrap();
+        openssl::base64::encode_block(&signer.sign_to_vec().unwrap())
+    }
+
+    /// Sends a signed message to a room
+    async fn send_message(&mut self, room: &str, content: &str) {
+        let signature = self.sign(content);
+        self.send(
+            MessagePacket::ID,
+            json!({ "room": room, "content": content, "signature": signature }),
+        )
+        .await;
+    }
+
+    /// Skips packets until one with `id` arrives
+    async fn expect(&mut self, id: &str) -> Value {
+        let found = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                let frame = self.socket.next().await.unwrap().unwrap();
+                let Message::Text(text) = frame else {
+                    continue;
+                };
+
+                let (packet_id, json_data) = text.split_once('|').unwrap();
+                if packet_id == id {
+                    return serde_json::from_str(json_data).unwrap();
+                }
+            }
+        })
+        .await;
+
+        found.unwrap_or_else(|_| panic!("timed out waiting for {}", id))
+    }
+}
+
+#[tokio::test]
+async fn message_is_kept_in_room_history() {
+    let (_server, address) = start_server().await;
+
+    let mut alice = TestClient::handshake(address, "alice").await;
+    alice.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    alice.send_message("general", "hello").await;
+
+    let echo = alice.expect(MessagePacket::ID).await;
+    assert_eq!(echo["content"], "hello");
+    assert_eq!(echo["sender_name"], "alice");
+
+    let mut bob = TestClient::handshake(address, "bob").await;
+    bob.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    bob.send(
+        HistoryRequest::ID,
+        json!({ "room": "general", "limit": 10 }),
+    )
+    .await;
+
+    let history = bob.expect(HistoryPage::ID).await;
+    assert_eq!(history["room"], "general");
+    assert_eq!(history["messages"][0]["content"], "hello");
+    assert_eq!(history["messages"][0]["sender_name"], "alice");
+    assert_eq!(history["cursor"], Value::Null);
+}
+
+#[tokio::test]
+async fn closed_connection_is_forgotten() {
+    let (server, address) = start_server().await;
+
+    let mut alice = TestClient::handshake(address, "alice").await;
+    alice.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    wait_until(|| async { !server.rooms.read().await.is_empty() }).await;
+    assert_eq!(server.map.read().await.len(), 1);
+
+    alice.socket.close(None).await.unwrap();
+
+    wait_until(|| async { server.map.read().await.is_empty() }).await;
+    assert!(server.rooms.read().await.is_empty());
+}
+
+#[tokio::test]
+async fn bad_history_cursor_is_rejected() {
+    let (server, address) = start_server().await;
+
+    let mut alice = TestClient::handshake(address, "alice").await;
+    alice.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    alice
+        .send(
+            HistoryRequest::ID,
+            json!({ "room": "general", "cursor": "not a cursor", "limit": 10 }),
+        )
+        .await;
+    let error = alice.expect(ErrorPacket::ID).await;
+    assert_eq!(error["code"], "malformed_packet");
+
+    alice.send_message("general", "still here").await;
+
+    let echo = alice.expect(MessagePacket::ID).await;
+    assert_eq!(echo["content"], "still here");
+    assert_eq!(server.map.read().await.len(), 1);
+}
+
+#[tokio::test]
+async fn edit_signature_is_bound_to_the_message() {
+    let (_server, address) = start_server().await;
+
+    let mut alice = TestClient::handshake(address, "alice").await;
+    alice.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    alice.send_message("general", "hello").await;
+    let id = alice.expect(MessagePacket::ID).await["id"].clone();
+
+    // A signature of the content alone, as a message carries, is no good
+    let replayed = alice.sign("edited");
+    alice
+        .send(
+            EditMessage::ID,
+            json!({ "room": "general", "id": id, "content": "edited", "signature": replayed }),
+        )
+        .await;
+
+    let signed_data = EditMessage::signed_data("general", id.as_str().unwrap(), "bound");
+    let signature = alice.sign(std::str::from_utf8(&signed_data).unwrap());
+    alice
+        .send(
+            EditMessage::ID,
+            json!({ "room": "general", "id": id, "content": "bound", "signature": signature }),
+        )
+        .await;
+
+    let edit = alice.expect(EditMessage::ID).await;
+    assert_eq!(edit["content"], "bound");
+}
+
+#[tokio::test]
+async fn handshake_needs_an_rsa_2048_key() {
+    let (_server, address) = start_server().await;
+
+    let key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+    let mut alice = TestClient::connect(address, key).await;
+    let public_key = String::from_utf8(alice.key.public_key_to_pem().unwrap()).unwrap();
+    alice
+        .send(
+            ServerboundHandshake::ID,
+            json!({ "name": "alice", "public_key": public_key }),
+        )
+        .await;
+
+    let error = alice.expect(ErrorPacket::ID).await;
+    assert_eq!(error["code"], "invalid_key");
+}
+
+#[tokio::test]
+async fn edit_and_delete_need_a_handshake() {
+    let (_server, address) = start_server().await;
+
+    let mut alice = TestClient::connect(address, TestClient::generate_key()).await;
+    let id = uuid::Uuid::now_v7().to_string();
+    let signature = alice.sign("anything");
+    alice
+        .send(
+            EditMessage::ID,
+            json!({ "room": "general", "id": id, "content": "edited", "signature": signature }),
+        )
+        .await;
+    let error = alice.expect(ErrorPacket::ID).await;
+    assert_eq!(error["code"], "no_handshake");
+
+    alice
+        .send(
+            DeleteMessage::ID,
+            json!({ "room": "general", "id": id, "signature": signature }),
+        )
+        .await;
+    let error = alice.expect(ErrorPacket::ID).await;
+    assert_eq!(error["code"], "no_handshake");
+}
+
+#[tokio::test]
+async fn typing_is_throttled_per_room() {
+    let (_server, address) = start_server().await;
+
+    let mut alice = TestClient::handshake_with_features(address, "alice", &[feature::TYPING]).await;
+    let mut bob = TestClient::handshake_with_features(address, "bob", &[feature::TYPING]).await;
+    for room in ["general", "random"] {
+        alice.send(JoinRoom::ID, json!({ "room": room })).await;
+        bob.send(JoinRoom::ID, json!({ "room": room })).await;
+    }
+    // Bob's joins are handled once his member list comes back
+    bob.send(ListMembers::ID, json!({})).await;
+    bob.expect(MemberList::ID).await;
+
+    // Typing in one room doesn't hold back an indicator for another
+    alice
+        .send(TypingStarted::ID, json!({ "room": "general" }))
+        .await;
+    alice
+        .send(TypingStarted::ID, json!({ "room": "random" }))
+        .await;
+    assert_eq!(bob.expect(TypingStarted::ID).await["room"], "general");
+    assert_eq!(bob.expect(TypingStarted::ID).await["room"], "random");
+
+    // Stopping lets the next indicator through straight away
+    alice
+        .send(TypingStopped::ID, json!({ "room": "general" }))
+        .await;
+    alice
+        .send(TypingStarted::ID, json!({ "room": "general" }))
+        .await;
+    bob.expect(TypingStopped::ID).await;
+    assert_eq!(bob.expect(TypingStarted::ID).await["room"], "general");
+}
+
+#[tokio::test]
+async fn read_position_needs_a_message_in_the_room() {
+    let (_server, address) = start_server().await;
+
+    let mut alice = TestClient::handshake(address, "alice").await;
+    alice.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    alice
+        .send(
+            ReadUpTo::ID,
+            json!({ "room": "general", "message_id": Uuid::now_v7().to_string() }),
+        )
+        .await;
+
+    let error = alice.expect(ErrorPacket::ID).await;
+    assert_eq!(error["code"], "unknown_message");
+}
+
+#[tokio::test]
+async fn queue_waits_for_a_client_that_can_ack() {
+    let (_server, address) = start_server().await;
+    let bob_key = TestClient::generate_key();
+    let v1 = json!({ "name": "bob" });
+    let v2 = json!({
+        "name": "bob",
+        "protocol_version": common::PROTOCOL_VERSION,
+        "features": [feature::QUEUE],
+    });
+
+    let mut alice = TestClient::handshake(address, "alice").await;
+    alice.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    let mut bob = TestClient::handshake_with(address, bob_key.clone(), v1.clone()).await;
+    bob.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    bob.send(ListMembers::ID, json!({})).await;
+    bob.expect(MemberList::ID).await;
+    bob.socket.close(None).await.unwrap();
+
+    alice.send_message("general", "first").await;
+    alice.expect(MessagePacket::ID).await;
+
+    // A client that can't ack leaves the queue alone
+    let mut bob = TestClient::handshake_with(address, bob_key.clone(), v1).await;
+    bob.send(ListMembers::ID, json!({})).await;
+    bob.expect(MemberList::ID).await;
+    bob.socket.close(None).await.unwrap();
+
+    let mut bob = TestClient::handshake_with(address, bob_key, v2).await;
+    assert_eq!(bob.expect(MessagePacket::ID).await["content"], "first");
+    let batch_end = bob.expect(QueueBatchEnd::ID).await;
+    bob.send(QueueAck::ID, json!({ "up_to": batch_end["up_to"] }))
+        .await;
+    bob.send(ListMembers::ID, json!({})).await;
+    bob.expect(MemberList::ID).await;
+
+    // Sent while bob is connected but hasn't rejoined the room yet
+    alice.send_message("general", "second").await;
+    alice.expect(MessagePacket::ID).await;
+
+    bob.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    assert_eq!(bob.expect(MessagePacket::ID).await["content"], "second");
+}
+
+#[tokio::test]
+async fn joining_sends_the_keys_of_offline_subscribers() {
+    let (server, address) = start_server().await;
+
+    let mut bob = TestClient::handshake(address, "bob").await;
+    bob.send(JoinRoom::ID, json!({ "room": "general" })).await;
+    bob.send(ListMembers::ID, json!({})).await;
+    bob.expect(MemberList::ID).await;
+    bob.socket.close(None).await.unwrap();
+    wait_until(|| async { server.map.read().await.is_empty() }).await;
+
+    let mut alice = TestClient::handshake(address, "alice").await;
+    alice.send(JoinRoom::ID, json!({ "room": "general" })).await;
+
+    let peer_key = alice.expect(PeerKeyPacket::ID).await;
+    assert_eq!(peer_key["name"], "bob");
+    let bob_key = String::from_utf8(bob.key.public_key_to_pem().unwrap()).unwrap();
+    assert_eq!(peer_key["public_key"], bob_key);
+}